@@ -8,6 +8,21 @@
 // import a full assembly file, which is what I want here.
 
 use core::arch::global_asm;
+use crate::config::{NCPU, NPROC};
+use crate::memlayout::{BOOT_STACK_SIZE, KSTACK_SLOT, TRAMPOLINE};
+use crate::riscv::PGSIZE;
 
-global_asm!(include_str!("boot.S"));
-global_asm!(include_str!("trap.S"));
+global_asm!(
+    include_str!("boot.S"),
+    NCPU = const NCPU,
+    BOOT_STACK_SIZE = const BOOT_STACK_SIZE,
+);
+global_asm!(
+    include_str!("trap.S"),
+    KSTACK_TOP = const TRAMPOLINE,
+    KSTACK_AREA = const NPROC as u64 * KSTACK_SLOT,
+    KSTACK_SLOT = const KSTACK_SLOT,
+    KSTACK_GUARD_OFFSET = const KSTACK_SLOT - PGSIZE,
+);
+global_asm!(include_str!("mem.S"));
+global_asm!(include_str!("swtch.S"));
@@ -0,0 +1,277 @@
+// Buddy-system allocator for physically contiguous runs of pages.
+// Memory is handed out in blocks of 2^order pages. Freeing a block
+// merges it with its buddy (the other half of the parent block)
+// whenever the buddy is free too, so large runs come back together.
+//
+// The block of order n starting at pa always has pa aligned to
+// 2^n pages, so the buddy of a block is found by flipping one bit:
+//   buddy(pa, n) = pa ^ (PGSIZE << n)
+use crate::fdt;
+use crate::memlayout;
+use crate::riscv::PGSIZE;
+use crate::spinlock::SpinLock;
+use crate::{print, println, PGROUNDDOWN, PGROUNDUP};
+use core::ptr;
+
+// largest block is 2^MAX_ORDER pages (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+// one entry of metadata for every page between KERNBASE and PHYSTOP.
+const NPAGES: usize = ((memlayout::PHYSTOP - memlayout::KERNBASE) / PGSIZE) as usize;
+
+// page metadata: the head page of a free block holds FREE | order,
+// the head page of an allocated block holds ALLOCATED | order, and
+// every other page holds NOT_HEAD.
+const FREE: u8 = 1 << 7;
+const ALLOCATED: u8 = 1 << 6;
+const NOT_HEAD: u8 = 0;
+
+// byte written over blocks when they are freed, to catch dangling refs.
+const FREE_JUNK: u8 = 1;
+// byte written over blocks when they are handed out.
+const ALLOC_JUNK: u8 = 5;
+
+// Every free block starts with a FreeBlock, linking it into the
+// freelist for its order. Doubly linked so a buddy can be unlinked
+// from the middle of a list when it is merged.
+struct FreeBlock {
+    next: *mut FreeBlock,
+    prev: *mut FreeBlock,
+}
+
+struct Buddy {
+    free: [*mut FreeBlock; MAX_ORDER + 1],
+    nfree: [u64; MAX_ORDER + 1], // free blocks of each order
+    meta: [u8; NPAGES],
+    start: u64, // first page managed by the allocator
+    end: u64,   // one past the last page managed by the allocator
+    npages: u64,
+}
+
+// the free blocks live in memory only the allocator touches.
+unsafe impl Send for Buddy {}
+
+static BUDDY: SpinLock<Buddy> = SpinLock::new(
+    Buddy {
+        free: [ptr::null_mut(); MAX_ORDER + 1],
+        nfree: [0; MAX_ORDER + 1],
+        meta: [NOT_HEAD; NPAGES],
+        start: 0,
+        end: 0,
+        npages: 0,
+    },
+    "buddy",
+);
+
+// Snapshot of the allocator, for the console.
+#[derive(Clone, Copy)]
+pub struct BuddyStats {
+    pub total_pages: u64,
+    pub free_pages: u64,
+    pub free_blocks: [u64; MAX_ORDER + 1],
+}
+
+impl BuddyStats {
+    // order of the largest free block, if any memory is free.
+    pub fn largest_free_order(&self) -> Option<usize> {
+        (0..=MAX_ORDER).rev().find(|&o| self.free_blocks[o] != 0)
+    }
+
+    // External fragmentation for requests of 2^order pages, in percent:
+    // how much of the free memory sits in blocks too small to serve them.
+    pub fn fragmentation(&self, order: usize) -> u64 {
+        if self.free_pages == 0 {
+            return 0;
+        }
+        let unusable: u64 = (0..order).map(|o| self.free_blocks[o] << o).sum();
+        unusable * 100 / self.free_pages
+    }
+}
+
+// smallest order whose blocks hold at least npages pages.
+pub fn order_for_pages(npages: u64) -> usize {
+    let mut order = 0;
+    while (1u64 << order) < npages {
+        order += 1;
+    }
+    order
+}
+
+// Hand every page from the end of the boot stack up to the end
+// of RAM to the allocator.
+pub fn init() {
+    let start = PGROUNDUP!(unsafe { memlayout::HEAP_START });
+    let end = PGROUNDDOWN!(unsafe { memlayout::MEMORY_END });
+    if start < memlayout::KERNBASE || end > memlayout::PHYSTOP {
+        panic!("buddy: heap 0x{:x}..0x{:x} outside of RAM", start, end);
+    }
+    let mut b = BUDDY.lock();
+    b.start = start;
+    b.end = end;
+    // leave the devicetree blob where the loader put it.
+    match fdt::reserved() {
+        Some((lo, hi)) if lo < end && hi > start => {
+            b.free_range(start, PGROUNDDOWN!(lo).max(start));
+            b.free_range(PGROUNDUP!(hi).min(end), end);
+        }
+        _ => b.free_range(start, end),
+    }
+}
+
+fn index(pa: u64) -> usize {
+    ((pa - memlayout::KERNBASE) / PGSIZE) as usize
+}
+
+impl Buddy {
+    // Carve [pa_start, pa_end) into the largest aligned blocks that fit
+    // and put them on the freelists.
+    fn free_range(&mut self, pa_start: u64, pa_end: u64) {
+        let mut pa = pa_start;
+        while pa < pa_end {
+            let mut order = MAX_ORDER;
+            while order > 0 && (!pa.is_multiple_of(PGSIZE << order) || pa + (PGSIZE << order) > pa_end) {
+                order -= 1;
+            }
+            unsafe {
+                ptr::write_bytes(pa as *mut u8, FREE_JUNK, (PGSIZE << order) as usize);
+            }
+            self.push(order, pa);
+            self.npages += 1 << order;
+            pa += PGSIZE << order;
+        }
+    }
+
+    fn push(&mut self, order: usize, pa: u64) {
+        let block = pa as *mut FreeBlock;
+        unsafe {
+            (*block).prev = ptr::null_mut();
+            (*block).next = self.free[order];
+            if !self.free[order].is_null() {
+                (*self.free[order]).prev = block;
+            }
+        }
+        self.free[order] = block;
+        self.nfree[order] += 1;
+        self.meta[index(pa)] = FREE | order as u8;
+    }
+
+    fn unlink(&mut self, order: usize, pa: u64) {
+        let block = pa as *mut FreeBlock;
+        unsafe {
+            if (*block).prev.is_null() {
+                self.free[order] = (*block).next;
+            } else {
+                (*(*block).prev).next = (*block).next;
+            }
+            if !(*block).next.is_null() {
+                (*(*block).next).prev = (*block).prev;
+            }
+        }
+        self.nfree[order] -= 1;
+        self.meta[index(pa)] = NOT_HEAD;
+    }
+
+    fn alloc(&mut self, order: usize) -> Option<u64> {
+        // find the smallest free block that is large enough.
+        let mut o = order;
+        while o <= MAX_ORDER && self.free[o].is_null() {
+            o += 1;
+        }
+        if o > MAX_ORDER {
+            return None;
+        }
+        let pa = self.free[o] as u64;
+        self.unlink(o, pa);
+
+        // split it, returning the upper halves to the freelists.
+        while o > order {
+            o -= 1;
+            self.push(o, pa + (PGSIZE << o));
+        }
+        self.meta[index(pa)] = ALLOCATED | order as u8;
+        Some(pa)
+    }
+
+    fn free(&mut self, pa: u64, order: usize) {
+        if order > MAX_ORDER
+            || !pa.is_multiple_of(PGSIZE << order)
+            || pa < self.start
+            || pa + (PGSIZE << order) > self.end
+        {
+            panic!("free_pages: bad block 0x{:x} order {}", pa, order);
+        }
+        if self.meta[index(pa)] != ALLOCATED | order as u8 {
+            panic!("free_pages: 0x{:x} is not an allocated block of order {}", pa, order);
+        }
+
+        // Fill with junk to catch dangling refs.
+        unsafe {
+            ptr::write_bytes(pa as *mut u8, FREE_JUNK, (PGSIZE << order) as usize);
+        }
+
+        let mut pa = pa;
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy_pa = pa ^ (PGSIZE << order);
+            if buddy_pa < self.start
+                || buddy_pa + (PGSIZE << order) > self.end
+                || self.meta[index(buddy_pa)] != FREE | order as u8
+            {
+                break;
+            }
+            self.unlink(order, buddy_pa);
+            self.meta[index(pa)] = NOT_HEAD;
+            pa = pa.min(buddy_pa);
+            order += 1;
+        }
+        self.push(order, pa);
+    }
+}
+
+// Allocate 2^order physically contiguous pages, aligned to their size.
+// Returns the physical address of the first page, or None if no
+// block that large is free.
+pub fn alloc_pages(order: usize) -> Option<u64> {
+    if order > MAX_ORDER {
+        return None;
+    }
+    let pa = BUDDY.lock().alloc(order)?;
+
+    // the block is ours now; fill it without holding the lock.
+    unsafe {
+        ptr::write_bytes(pa as *mut u8, ALLOC_JUNK, (PGSIZE << order) as usize);
+    }
+    Some(pa)
+}
+
+// Free a block returned by alloc_pages(order), merging it with its
+// buddy for as long as the buddy is free.
+pub fn free_pages(pa: u64, order: usize) {
+    BUDDY.lock().free(pa, order);
+}
+
+pub fn stats() -> BuddyStats {
+    let b = BUDDY.lock();
+    let free_pages = (0..=MAX_ORDER).map(|o| b.nfree[o] << o).sum();
+    BuddyStats {
+        total_pages: b.npages,
+        free_pages,
+        free_blocks: b.nfree,
+    }
+}
+
+pub fn print_stats() {
+    let s = stats();
+    println!(
+        "buddy: {} of {} pages free ({} KiB), fragmentation {}%",
+        s.free_pages,
+        s.total_pages,
+        s.free_pages * PGSIZE / 1024,
+        s.fragmentation(MAX_ORDER)
+    );
+    print!("  free blocks by order:");
+    for (order, n) in s.free_blocks.iter().enumerate() {
+        print!(" {}:{}", order, n);
+    }
+    println!();
+}
@@ -1 +1,7 @@
 pub const NCPU: u8 = 4; 
+pub const NPROC: usize = 64;  // maximum number of processes
+pub const KSTACK_PAGES: usize = 4; // pages in each process's kernel stack
+pub const TICK_HZ: u64 = 100; // timer interrupts per second
+pub const TIMER_PREFER_SSTC: bool = true; // use stimecmp when the hart has Sstc
+pub const NUART: usize = 4; // maximum number of serial ports
+pub const NHISTORY: usize = 32; // shell history lines kept
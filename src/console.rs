@@ -0,0 +1,584 @@
+// Console input and output, to the console UART.
+// Based on console.c from MIT 6.1810 (xv6).
+//
+// Reads are line at a time in canonical mode, with these
+// special input characters:
+//   newline -- end of line
+//   control-h / delete -- backspace
+//   control-u -- kill line (before the cursor)
+//   control-w -- kill word (before the cursor)
+//   control-d -- end of file
+//   control-c -- interrupt the foreground task
+//   control-a / Home -- start of line
+//   control-e / End -- end of line
+//   left / right arrows -- move the cursor
+//   Delete -- delete the character under the cursor
+//   up / down arrows -- scroll through the history (history.rs)
+//   tab -- complete the word before the cursor, using the
+//          completer set with set_completer(); a second tab
+//          lists the choices
+// Typing in the middle of a line inserts at the cursor; the
+// rest of the line is redrawn with ANSI escape sequences.
+// In raw mode every byte is passed through as it arrives,
+// without echo.
+//
+// The console UART's interrupt handler hands each input byte
+// to consoleintr(), which does the editing and echoing.
+use crate::history::{self, Line};
+use crate::proc::{self, Chan};
+use crate::spinlock::SpinLock;
+use crate::uart;
+use crate::print;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+const ESC: u8 = 0x1b;
+const TAB: u8 = b'\t';
+const BELL: u8 = 0x07;
+
+// Control-x
+const fn ctrl(x: u8) -> u8 {
+    x - b'@'
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Mode {
+    Canonical,
+    Raw,
+}
+
+// where consoleintr() is in an ANSI escape sequence.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    Esc, // saw ESC
+    Csi, // saw ESC [, then maybe a numeric parameter
+    Ss3, // saw ESC O
+}
+
+const INPUT_BUF_SIZE: usize = 128;
+
+struct Cons {
+    buf: [u8; INPUT_BUF_SIZE],
+    r: usize, // Read index
+    w: usize, // Write index
+    e: usize, // Edit index
+    cur: usize, // Cursor, between w and e
+    mode: Mode,
+    escape: Escape,
+    param: usize, // CSI numeric parameter
+    // the history line being shown, or 0 for the line
+    // being typed, which is kept in draft meanwhile.
+    hist: u64,
+    draft: Line,
+    // the last key was a tab that left choices open, so the
+    // next one lists them.
+    tabbed: bool,
+    completer: Option<Completer>,
+    // printed again after a tab lists the choices.
+    prompt: &'static str,
+}
+
+static CONS: SpinLock<Cons> = SpinLock::new(
+    Cons {
+        buf: [0; INPUT_BUF_SIZE],
+        r: 0,
+        w: 0,
+        e: 0,
+        cur: 0,
+        mode: Mode::Canonical,
+        escape: Escape::None,
+        param: 0,
+        hist: 0,
+        draft: Line::new(),
+        tabbed: false,
+        completer: None,
+        prompt: "",
+    },
+    "cons",
+);
+
+// Line editing. The line being edited is buf[w..e]; the
+// terminal's cursor sits at the character cur.
+impl Cons {
+    fn at(&self, i: usize) -> u8 {
+        self.buf[i % INPUT_BUF_SIZE]
+    }
+
+    fn set(&mut self, i: usize, c: u8) {
+        self.buf[i % INPUT_BUF_SIZE] = c;
+    }
+
+    // echo the line from from to its end; the terminal's
+    // cursor ends up after it.
+    fn echo_tail(&self, from: usize) {
+        for i in from..self.e {
+            consputc(self.at(i));
+        }
+    }
+
+    // insert c at the cursor. one slot is always kept
+    // free so that newline or control-d can end the line.
+    fn insert(&mut self, c: u8) {
+        if self.e - self.r >= INPUT_BUF_SIZE - 1 {
+            return;
+        }
+        for i in (self.cur..self.e).rev() {
+            self.set(i + 1, self.at(i));
+        }
+        self.set(self.cur, c);
+        self.e += 1;
+        self.cur += 1;
+        consputc(c);
+        self.echo_tail(self.cur);
+        cursor_left(self.e - self.cur);
+    }
+
+    // delete up to n characters before the cursor.
+    fn delete_before(&mut self, n: usize) {
+        let n = n.min(self.cur - self.w);
+        if n == 0 {
+            return;
+        }
+        cursor_left(n);
+        for i in self.cur..self.e {
+            self.set(i - n, self.at(i));
+        }
+        self.cur -= n;
+        self.e -= n;
+        self.echo_tail(self.cur);
+        for _ in 0..n {
+            consputc(b' ');
+        }
+        cursor_left(self.e - self.cur + n);
+    }
+
+    // delete the character under the cursor.
+    fn delete_at(&mut self) {
+        if self.cur == self.e {
+            return;
+        }
+        consputc(self.at(self.cur));
+        self.cur += 1;
+        self.delete_before(1);
+    }
+
+    fn move_to(&mut self, pos: usize) {
+        if pos < self.cur {
+            cursor_left(self.cur - pos);
+        } else {
+            cursor_right(pos - self.cur);
+        }
+        self.cur = pos;
+    }
+
+    // the length of the word before the cursor, and the
+    // spaces after it.
+    fn word_before(&self) -> usize {
+        let mut i = self.cur;
+        while i != self.w && self.at(i - 1) == b' ' {
+            i -= 1;
+        }
+        while i != self.w && self.at(i - 1) != b' ' {
+            i -= 1;
+        }
+        self.cur - i
+    }
+
+    // end the line with c and hand it to read().
+    fn commit(&mut self, c: u8) {
+        self.set(self.e, c);
+        self.e += 1;
+        self.w = self.e;
+        self.cur = self.e;
+        self.hist = 0;
+    }
+
+    fn line(&self) -> Line {
+        let mut line = [0u8; INPUT_BUF_SIZE];
+        let len = self.e - self.w;
+        for (i, c) in line.iter_mut().take(len).enumerate() {
+            *c = self.at(self.w + i);
+        }
+        Line::from(&line[..len])
+    }
+
+    // replace the whole line with s, cursor at the end.
+    fn replace(&mut self, s: &[u8]) {
+        cursor_left(self.cur - self.w);
+        // "erase to end of line".
+        print!("\x1b[K");
+        self.e = self.w;
+        self.cur = self.w;
+        for &c in s.iter().take(INPUT_BUF_SIZE - 1 - (self.w - self.r)) {
+            self.set(self.e, c);
+            self.e += 1;
+            consputc(c);
+        }
+        self.cur = self.e;
+    }
+
+    // show an older (up) or newer (down) history line.
+    fn scroll(&mut self, up: bool) {
+        let n = if up {
+            match self.hist {
+                0 => history::newest(),
+                n if n > history::oldest() => n - 1,
+                _ => return,
+            }
+        } else {
+            match self.hist {
+                0 => return,
+                n => n + 1,
+            }
+        };
+        match history::get(n) {
+            Some(line) => {
+                if self.hist == 0 {
+                    self.draft = self.line();
+                }
+                self.hist = n;
+                self.replace(line.as_bytes());
+            }
+            None if !up => {
+                // past the newest: back to what was being typed.
+                self.hist = 0;
+                let draft = self.draft;
+                self.replace(draft.as_bytes());
+            }
+            None => {}
+        }
+    }
+
+    // the tab key.
+    fn complete(&mut self) {
+        let Some(completer) = self.completer else {
+            consputc(BELL);
+            return;
+        };
+        let mut before = [0u8; INPUT_BUF_SIZE];
+        let len = self.cur - self.w;
+        for (i, c) in before.iter_mut().take(len).enumerate() {
+            *c = self.at(self.w + i);
+        }
+        let Ok(line) = core::str::from_utf8(&before[..len]) else {
+            consputc(BELL);
+            return;
+        };
+        let word = &line[line.rfind(' ').map_or(0, |i| i + 1)..];
+
+        let mut comps = Completions::new(word);
+        completer(line, &mut comps);
+
+        let common = comps.common();
+        if common.len() > word.len() || (comps.found().len() == 1 && !self.tabbed) {
+            // complete as far as the choices agree, and finish
+            // the word if there's only one.
+            for c in common[word.len()..].bytes() {
+                self.insert(c);
+            }
+            if comps.found().len() == 1 && (self.cur == self.e || self.at(self.cur) != b' ') {
+                self.insert(b' ');
+            }
+            self.tabbed = comps.found().len() > 1;
+        } else if self.tabbed && !comps.found().is_empty() {
+            // second tab: list the choices and redraw the line.
+            consputc(b'\r');
+            consputc(b'\n');
+            for c in comps.found() {
+                print!("{}  ", c);
+            }
+            consputc(b'\r');
+            consputc(b'\n');
+            print!("{}", self.prompt);
+            self.echo_tail(self.w);
+            cursor_left(self.e - self.cur);
+        } else {
+            consputc(BELL);
+            self.tabbed = true;
+        }
+    }
+
+    // act on a complete escape sequence: final is the last byte,
+    // param the number before it (0 if none).
+    fn escape_seq(&mut self, final_byte: u8, param: usize) {
+        match (final_byte, param) {
+            (b'C', _) if self.cur < self.e => self.move_to(self.cur + 1),
+            (b'D', _) if self.cur > self.w => self.move_to(self.cur - 1),
+            (b'H', _) | (b'~', 1) | (b'~', 7) => self.move_to(self.w),
+            (b'F', _) | (b'~', 4) | (b'~', 8) => self.move_to(self.e),
+            (b'~', 3) => self.delete_at(),
+            (b'A', _) => self.scroll(true),
+            (b'B', _) => self.scroll(false),
+            _ => {}
+        }
+    }
+}
+
+fn cursor_left(n: usize) {
+    if n > 0 {
+        print!("\x1b[{}D", n);
+    }
+}
+
+fn cursor_right(n: usize) {
+    if n > 0 {
+        print!("\x1b[{}C", n);
+    }
+}
+
+// most candidates a completer can offer.
+const MAX_COMPLETIONS: usize = 32;
+
+// The choices for completing a word, gathered by a completer.
+pub struct Completions<'a> {
+    word: &'a str,
+    found: [&'static str; MAX_COMPLETIONS],
+    n: usize,
+}
+
+impl<'a> Completions<'a> {
+    fn new(word: &'a str) -> Self {
+        Completions {
+            word,
+            found: [""; MAX_COMPLETIONS],
+            n: 0,
+        }
+    }
+
+    // the partial word being completed.
+    pub fn word(&self) -> &str {
+        self.word
+    }
+
+    // offer candidate, if it completes the word.
+    pub fn offer(&mut self, candidate: &'static str) {
+        if candidate.starts_with(self.word) && self.n < MAX_COMPLETIONS && !self.found[..self.n].contains(&candidate) {
+            self.found[self.n] = candidate;
+            self.n += 1;
+        }
+    }
+
+    pub fn found(&self) -> &[&'static str] {
+        &self.found[..self.n]
+    }
+
+    // the longest prefix all the candidates share.
+    fn common(&self) -> &'static str {
+        let Some(first) = self.found().first() else {
+            return "";
+        };
+        let mut len = first.len();
+        for c in &self.found()[1..] {
+            len = len.min(first.bytes().zip(c.bytes()).take_while(|(a, b)| a == b).count());
+        }
+        &first[..len]
+    }
+}
+
+// A completer gets the line up to the cursor; the word to complete
+// is its last word, already in Completions::word().
+pub type Completer = fn(line: &str, out: &mut Completions);
+
+pub fn set_completer(completer: Option<Completer>) {
+    CONS.lock().completer = completer;
+}
+
+// Print the prompt for a line, and remember it
+// for redrawing the line.
+pub fn prompt(prompt: &'static str) {
+    CONS.lock().prompt = prompt;
+    print!("{}", prompt);
+}
+
+// set by control-c, cleared by take_interrupt().
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// send one character to the uart.
+fn consputc(c: u8) {
+    uart::uartputc(c);
+}
+
+pub fn mode() -> Mode {
+    CONS.lock().mode
+}
+
+// Switch between canonical and raw input. Anything typed
+// but not yet committed is discarded.
+pub fn set_mode(mode: Mode) {
+    let mut cons = CONS.lock();
+    cons.e = cons.w;
+    cons.cur = cons.w;
+    cons.escape = Escape::None;
+    cons.mode = mode;
+}
+
+// Did someone type control-c since the last call?
+// The foreground task polls this to know when to stop.
+pub fn take_interrupt() -> bool {
+    INTERRUPTED.swap(false, Ordering::Relaxed)
+}
+
+// user write()s to the console go here.
+// newlines go out as CR LF.
+pub fn write(src: &[u8]) -> usize {
+    for &c in src {
+        if c == b'\n' {
+            consputc(b'\r');
+        }
+        consputc(c);
+    }
+    src.len()
+}
+
+// user read()s from the console go here.
+// copy (up to) a whole input line to dst.
+// in canonical mode, waits for a line; returns 0 at end of
+// file (control-d at the start of a line). in raw mode,
+// waits for at least one byte. stops waiting if control-c
+// is typed, so check take_interrupt() after a short read.
+pub fn read(dst: &mut [u8]) -> usize {
+    let mut n = 0;
+
+    let mut cons = CONS.lock();
+    while n < dst.len() {
+        // wait until interrupt handler has put some
+        // input into cons.buf.
+        while cons.r == cons.w {
+            if (n > 0 && cons.mode == Mode::Raw) || INTERRUPTED.load(Ordering::Relaxed) {
+                break;
+            }
+            cons = proc::sleep(input_chan(), cons);
+        }
+        if cons.r == cons.w {
+            break;
+        }
+
+        let c = cons.buf[cons.r % INPUT_BUF_SIZE];
+        cons.r += 1;
+
+        if cons.mode == Mode::Canonical && c == ctrl(b'D') {
+            // end-of-file
+            if n > 0 {
+                // Save ^D for next time, to make sure
+                // caller gets a 0-byte result.
+                cons.r -= 1;
+            }
+            break;
+        }
+
+        dst[n] = c;
+        n += 1;
+
+        if cons.mode == Mode::Canonical && c == b'\n' {
+            // a whole line has arrived, return to
+            // the user-level read().
+            break;
+        }
+    }
+
+    n
+}
+
+// what read() sleeps on.
+fn input_chan() -> Chan {
+    &CONS as *const _ as Chan
+}
+
+// the console input interrupt handler.
+// uartintr() calls this for each input character of the
+// console UART. do line editing, append to cons.buf,
+// and wake up read() if a whole line has arrived.
+pub fn consoleintr(c: u8) {
+    let mut guard = CONS.lock();
+    let cons = &mut *guard;
+
+    if cons.mode == Mode::Raw {
+        if cons.e - cons.r < INPUT_BUF_SIZE {
+            cons.buf[cons.e % INPUT_BUF_SIZE] = c;
+            cons.e += 1;
+            cons.w = cons.e;
+            cons.cur = cons.e;
+            proc::wakeup(input_chan());
+        }
+        return;
+    }
+
+    // collect escape sequences (arrow keys and the like).
+    match cons.escape {
+        Escape::Esc => {
+            cons.param = 0;
+            cons.escape = match c {
+                b'[' => Escape::Csi,
+                b'O' => Escape::Ss3,
+                _ => Escape::None,
+            };
+            return;
+        }
+        Escape::Csi => {
+            if c.is_ascii_digit() {
+                // line noise can carry any number of digits.
+                cons.param = cons.param.saturating_mul(10).saturating_add((c - b'0') as usize);
+            } else if (0x40..=0x7e).contains(&c) {
+                // a final byte ends the sequence.
+                cons.escape = Escape::None;
+                cons.escape_seq(c, cons.param);
+            }
+            return;
+        }
+        Escape::Ss3 => {
+            cons.escape = Escape::None;
+            cons.escape_seq(c, 0);
+            return;
+        }
+        Escape::None => {}
+    }
+
+    if c != TAB {
+        cons.tabbed = false;
+    }
+
+    match c {
+        ESC => cons.escape = Escape::Esc,
+        TAB => cons.complete(),
+        c if c == ctrl(b'U') => {
+            // Kill line.
+            cons.delete_before(cons.cur - cons.w);
+        }
+        c if c == ctrl(b'W') => {
+            // Kill word.
+            cons.delete_before(cons.word_before());
+        }
+        BACKSPACE | DELETE => cons.delete_before(1),
+        c if c == ctrl(b'A') => cons.move_to(cons.w),
+        c if c == ctrl(b'E') => cons.move_to(cons.e),
+        c if c == ctrl(b'C') => {
+            // throw away the line and tell the foreground task.
+            cons.e = cons.w;
+            cons.cur = cons.w;
+            cons.hist = 0;
+            consputc(b'^');
+            consputc(b'C');
+            consputc(b'\r');
+            consputc(b'\n');
+            INTERRUPTED.store(true, Ordering::Relaxed);
+            proc::wakeup(input_chan());
+        }
+        b'\r' | b'\n' => {
+            // echo back to the user, and wake up read().
+            consputc(b'\r');
+            consputc(b'\n');
+            cons.commit(b'\n');
+            proc::wakeup(input_chan());
+        }
+        c if c == ctrl(b'D') => {
+            // end-of-file: wake up read() with what there is.
+            cons.commit(c);
+            proc::wakeup(input_chan());
+        }
+        c if c >= b' ' => cons.insert(c),
+        // other control characters.
+        _ => {}
+    }
+}
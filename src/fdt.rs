@@ -0,0 +1,315 @@
+// Flattened device tree (devicetree blob) lookups.
+//
+// qemu passes the physical address of the blob in a1 when it jumps
+// to _entry; boot.S hands it to start(), which records it here.
+// This is just enough of a parser to find nodes and read their
+// properties. It doesn't allocate, so it works in machine mode
+// before the page allocator exists.
+//
+// The blob lives at the top of RAM, inside the range the page
+// allocator would otherwise hand out, so buddy::init() leaves
+// its pages alone (see reserved()).
+use core::ptr::addr_of;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+
+// structure block tokens.
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+// header field offsets.
+const HDR_TOTALSIZE: usize = 4;
+const HDR_OFF_DT_STRUCT: usize = 8;
+const HDR_OFF_DT_STRINGS: usize = 12;
+
+// deepest node whose #address-cells/#size-cells we track.
+const MAX_DEPTH: usize = 16;
+
+static mut FDT: u64 = 0;
+
+// Remember where the blob is. Ignores anything without the
+// devicetree magic number, so the kernel still boots (on its
+// built-in defaults) if the loader didn't pass one.
+pub fn init(pa: u64) {
+    if pa != 0 && pa.is_multiple_of(8) && unsafe { be32(pa as *const u8) } == FDT_MAGIC {
+        unsafe { FDT = pa };
+    }
+}
+
+// The devicetree the kernel was booted with, if any.
+pub fn get() -> Option<Fdt> {
+    let pa = unsafe { *addr_of!(FDT) };
+    if pa == 0 {
+        return None;
+    }
+    let size = unsafe { be32((pa as usize + HDR_TOTALSIZE) as *const u8) };
+    let blob = unsafe { core::slice::from_raw_parts(pa as *const u8, size as usize) };
+    Some(Fdt { blob })
+}
+
+// Physical address range occupied by the blob, if any.
+pub fn reserved() -> Option<(u64, u64)> {
+    get().map(|fdt| (fdt.addr(), fdt.addr() + fdt.blob.len() as u64))
+}
+
+unsafe fn be32(p: *const u8) -> u32 {
+    u32::from_be_bytes(unsafe { [*p, *p.add(1), *p.add(2), *p.add(3)] })
+}
+
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt {
+    blob: &'static [u8],
+}
+
+impl Fdt {
+    pub fn addr(&self) -> u64 {
+        self.blob.as_ptr() as u64
+    }
+
+    fn u32_at(&self, off: usize) -> u32 {
+        match self.blob.get(off..off + 4) {
+            Some(b) => u32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+            None => FDT_END,
+        }
+    }
+
+    // the nul-terminated string at off, and the offset just past it.
+    fn cstr_at(&self, off: usize) -> (&'static str, usize) {
+        let bytes = self.blob.get(off..).unwrap_or(&[]);
+        let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+        let s = core::str::from_utf8(&bytes[..len]).unwrap_or("");
+        (s, off + len + 1)
+    }
+
+    fn prop_name(&self, nameoff: u32) -> &'static str {
+        self.cstr_at(self.u32_at(HDR_OFF_DT_STRINGS) as usize + nameoff as usize).0
+    }
+
+    // Every node, in the order they appear in the blob (parents
+    // before their children), starting with the root.
+    pub fn nodes(&self) -> Nodes {
+        Nodes {
+            fdt: *self,
+            off: self.u32_at(HDR_OFF_DT_STRUCT) as usize,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH + 1],
+        }
+    }
+
+    // Look a node up by path, like "/cpus/cpu@0" or "/chosen".
+    // A component without a unit address matches any unit address,
+    // so "/soc/serial" finds the first serial@... under /soc.
+    pub fn find(&self, path: &str) -> Option<Node> {
+        let mut comps = [""; MAX_DEPTH];
+        let mut ncomps = 0;
+        for c in path.split('/').filter(|c| !c.is_empty()) {
+            if ncomps == MAX_DEPTH {
+                return None;
+            }
+            comps[ncomps] = c;
+            ncomps += 1;
+        }
+
+        // number of path components matched by the current node's ancestors.
+        let mut matched = 0;
+        for node in self.nodes() {
+            if node.depth == 0 {
+                if ncomps == 0 {
+                    return Some(node);
+                }
+                continue;
+            }
+            if node.depth - 1 > matched {
+                continue;
+            }
+            matched = node.depth - 1;
+            if node_name_matches(node.name, comps[matched]) {
+                matched += 1;
+                if matched == ncomps {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    // Nodes whose compatible list includes compat.
+    pub fn compatible<'a>(&self, compat: &'a str) -> impl Iterator<Item = Node> + 'a {
+        self.nodes().filter(move |n| n.is_compatible(compat))
+    }
+
+    // The node a "phandle" property refers to.
+    pub fn by_phandle(&self, phandle: u32) -> Option<Node> {
+        self.nodes().find(|n| n.prop_u32("phandle") == Some(phandle))
+    }
+}
+
+fn node_name_matches(name: &str, comp: &str) -> bool {
+    name == comp || (!comp.contains('@') && name.split('@').next() == Some(comp))
+}
+
+pub struct Nodes {
+    fdt: Fdt,
+    off: usize,
+    depth: usize,
+    // (#address-cells, #size-cells) that apply to the children
+    // of the node at each depth.
+    cells: [(u32, u32); MAX_DEPTH + 1],
+}
+
+impl Iterator for Nodes {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        loop {
+            let token = self.fdt.u32_at(self.off);
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let (name, end) = self.fdt.cstr_at(self.off);
+                    self.off = align4(end);
+                    let depth = self.depth;
+                    let (address_cells, size_cells) = self.cells[depth.min(MAX_DEPTH)];
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth,
+                        props: self.off,
+                        address_cells,
+                        size_cells,
+                    };
+                    // the node's own #address-cells/#size-cells
+                    // describe its children's reg properties.
+                    let child = (
+                        node.prop_u32("#address-cells").unwrap_or(2),
+                        node.prop_u32("#size-cells").unwrap_or(1),
+                    );
+                    self.depth += 1;
+                    self.cells[self.depth.min(MAX_DEPTH)] = child;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.saturating_sub(1);
+                }
+                FDT_PROP => {
+                    let len = self.fdt.u32_at(self.off) as usize;
+                    self.off = align4(self.off + 8 + len);
+                }
+                FDT_NOP => {}
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Node {
+    fdt: Fdt,
+    pub name: &'static str,
+    pub depth: usize,
+    props: usize, // offset of the first token after the name
+    // cells used by this node's reg property, from its parent.
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl Node {
+    // The node's own properties, as (name, value) pairs.
+    pub fn props(&self) -> Props {
+        Props {
+            fdt: self.fdt,
+            off: self.props,
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        self.props().find(|&(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    // a string property, without its terminating nul.
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        self.prop_strs(name).next()
+    }
+
+    // the strings in a stringlist property like "compatible".
+    pub fn prop_strs(&self, name: &str) -> impl Iterator<Item = &'static str> {
+        let v = self.prop(name).unwrap_or(&[]);
+        let v = v.strip_suffix(&[0]).unwrap_or(v);
+        v.split(|&c| c == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| core::str::from_utf8(s).unwrap_or(""))
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        let v = self.prop(name)?;
+        Some(u32::from_be_bytes(v.get(0..4)?.try_into().ok()?))
+    }
+
+    // a one- or two-cell number, like "clock-frequency".
+    pub fn prop_u64(&self, name: &str) -> Option<u64> {
+        let v = self.prop(name)?;
+        match v.len() {
+            4 => Some(u32::from_be_bytes(v.try_into().ok()?) as u64),
+            8 => Some(u64::from_be_bytes(v.try_into().ok()?)),
+            _ => None,
+        }
+    }
+
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop_strs("compatible").any(|c| c == compat)
+    }
+
+    // The first (address, size) pair of the reg property, decoded
+    // with the parent's #address-cells and #size-cells.
+    pub fn reg(&self) -> Option<(u64, u64)> {
+        let v = self.prop("reg")?;
+        let a = self.address_cells as usize * 4;
+        let s = self.size_cells as usize * 4;
+        Some((cells(v.get(0..a)?)?, cells(v.get(a..a + s)?)?))
+    }
+}
+
+// a big-endian number of zero, one or two cells.
+fn cells(v: &[u8]) -> Option<u64> {
+    match v.len() {
+        0 => Some(0),
+        4 => Some(u32::from_be_bytes(v.try_into().ok()?) as u64),
+        8 => Some(u64::from_be_bytes(v.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+pub struct Props {
+    fdt: Fdt,
+    off: usize,
+}
+
+impl Iterator for Props {
+    type Item = (&'static str, &'static [u8]);
+
+    // properties come before any child nodes, so stop at the
+    // first token that isn't one.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.fdt.u32_at(self.off) {
+                FDT_PROP => {
+                    let len = self.fdt.u32_at(self.off + 4) as usize;
+                    let nameoff = self.fdt.u32_at(self.off + 8);
+                    let start = self.off + 12;
+                    self.off = align4(start + len);
+                    let value = self.fdt.blob.get(start..start + len).unwrap_or(&[]);
+                    return Some((self.fdt.prop_name(nameoff), value));
+                }
+                FDT_NOP => self.off += 4,
+                _ => return None,
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Kernel heap, so that the `alloc` crate (Box, Vec, BTreeMap, ...)
+// can be used inside the kernel.
+//
+// Small requests (up to MAX_SMALL bytes) are served from the kmalloc-*
+// slab caches (slab.rs), one per power-of-two size, whose objects are
+// aligned to their size. Larger requests get their own run of pages
+// from the buddy allocator.
+use crate::buddy;
+use crate::riscv::PGSIZE;
+use crate::slab::{self, KmemCache};
+use crate::{print, println};
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// smallest class is 16 bytes, largest is 2048 bytes.
+const MIN_SHIFT: usize = 4;
+const NCLASSES: usize = 8;
+pub const MAX_SMALL: usize = 1 << (MIN_SHIFT + NCLASSES - 1);
+
+static KMALLOC: [KmemCache; NCLASSES] = [
+    KmemCache::new("kmalloc-16", 16, 16),
+    KmemCache::new("kmalloc-32", 32, 32),
+    KmemCache::new("kmalloc-64", 64, 64),
+    KmemCache::new("kmalloc-128", 128, 128),
+    KmemCache::new("kmalloc-256", 256, 256),
+    KmemCache::new("kmalloc-512", 512, 512),
+    KmemCache::new("kmalloc-1024", 1024, 1024),
+    KmemCache::new("kmalloc-2048", 2048, 2048),
+];
+
+// pages held by large allocations.
+static LARGE_PAGES: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    for cache in KMALLOC.iter() {
+        slab::register(cache);
+    }
+}
+
+// size class for a layout, or None if it needs whole pages.
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_SHIFT);
+    if size > MAX_SMALL {
+        return None;
+    }
+    Some(size.next_power_of_two().trailing_zeros() as usize - MIN_SHIFT)
+}
+
+// buddy order for a layout too large for the size classes. Buddy blocks
+// are aligned to their size, which covers any alignment request.
+fn order_of(layout: &Layout) -> usize {
+    let bytes = layout.size().max(layout.align()) as u64;
+    buddy::order_for_pages(bytes.div_ceil(PGSIZE))
+}
+
+// size and alignment of the last request the heap couldn't
+// satisfy, or 0. the infallible paths (Box::new, Vec::push, ...)
+// go on to panic in handle_alloc_error(), which only knows the
+// size; the panic handler prints the layout from here.
+static FAILED_SIZE: AtomicU64 = AtomicU64::new(0);
+static FAILED_ALIGN: AtomicU64 = AtomicU64::new(0);
+
+// The layout of the last allocation that failed, if any.
+pub fn failed_layout() -> Option<(u64, u64)> {
+    match FAILED_ALIGN.load(Ordering::Relaxed) {
+        0 => None,
+        align => Some((FAILED_SIZE.load(Ordering::Relaxed), align)),
+    }
+}
+
+pub struct KernelHeap;
+
+// Out of memory is a null pointer, as GlobalAlloc requires, so that
+// try_reserve() and friends see it.
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let p = match class_of(&layout) {
+            Some(class) => KMALLOC[class].alloc(),
+            None => {
+                let order = order_of(&layout);
+                buddy::alloc_pages(order).map(|pa| {
+                    LARGE_PAGES.fetch_add(1 << order, Ordering::Relaxed);
+                    pa as *mut u8
+                })
+            }
+        };
+        p.unwrap_or_else(|| {
+            FAILED_SIZE.store(layout.size() as u64, Ordering::Relaxed);
+            FAILED_ALIGN.store(layout.align() as u64, Ordering::Relaxed);
+            ptr::null_mut()
+        })
+    }
+
+    unsafe fn dealloc(&self, p: *mut u8, layout: Layout) {
+        match class_of(&layout) {
+            Some(class) => KMALLOC[class].free(p),
+            None => {
+                let order = order_of(&layout);
+                buddy::free_pages(p as u64, order);
+                LARGE_PAGES.fetch_sub(1 << order, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[global_allocator]
+static KERNEL_HEAP: KernelHeap = KernelHeap;
+
+// the heap's totals; slab::print_stats() lists the
+// kmalloc-* caches with the others.
+pub fn print_stats() {
+    let small: u64 = KMALLOC.iter().map(|c| c.stats()).map(|s| s.inuse * s.size).sum();
+    println!(
+        "heap: {} bytes in small allocations, {} pages in large allocations",
+        small,
+        LARGE_PAGES.load(Ordering::Relaxed)
+    );
+}
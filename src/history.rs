@@ -0,0 +1,152 @@
+// Command history for the kernel shell.
+//
+// A fixed ring of the last NHISTORY lines. Lines are numbered from 1
+// in the order they were added, and keep their numbers as older ones
+// fall off the ring. The console scrolls through them with the up
+// and down arrows; the shell adds what it runs and expands !n and !!.
+use crate::config::NHISTORY;
+use crate::{print, println};
+use crate::spinlock::SpinLock;
+use core::fmt;
+
+// longest line kept, the size of the console's input buffer.
+pub const HISTORY_LINE: usize = 128;
+
+#[derive(Clone, Copy)]
+pub struct Line {
+    buf: [u8; HISTORY_LINE],
+    len: usize,
+}
+
+impl Line {
+    pub const fn new() -> Self {
+        Line {
+            buf: [0; HISTORY_LINE],
+            len: 0,
+        }
+    }
+
+    // a copy of the first HISTORY_LINE bytes of s.
+    pub fn from(s: &[u8]) -> Self {
+        let mut line = Line::new();
+        line.len = s.len().min(HISTORY_LINE);
+        line.buf[..line.len].copy_from_slice(&s[..line.len]);
+        line
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(self.as_bytes()).unwrap_or("?")
+    }
+}
+
+impl Default for Line {
+    fn default() -> Self {
+        Line::new()
+    }
+}
+
+struct History {
+    lines: [Line; NHISTORY],
+    // number of the newest line; lines older than
+    // newest - NHISTORY have been overwritten.
+    newest: u64,
+}
+
+static HISTORY: SpinLock<History> = SpinLock::new(
+    History {
+        lines: [Line::new(); NHISTORY],
+        newest: 0,
+    },
+    "history",
+);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HistoryError {
+    // !n or !! named a line that isn't in the ring.
+    NotFound,
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HistoryError::NotFound => write!(f, "event not found"),
+        }
+    }
+}
+
+impl History {
+    fn oldest(&self) -> u64 {
+        if self.newest == 0 {
+            0
+        } else {
+            self.newest.saturating_sub(NHISTORY as u64 - 1).max(1)
+        }
+    }
+
+    fn get(&self, n: u64) -> Option<Line> {
+        if n == 0 || n < self.oldest() || n > self.newest {
+            return None;
+        }
+        Some(self.lines[((n - 1) % NHISTORY as u64) as usize])
+    }
+}
+
+// Number of the oldest line still in the ring.
+pub fn oldest() -> u64 {
+    HISTORY.lock().oldest()
+}
+
+// Number of the newest line, or 0 if there are none.
+pub fn newest() -> u64 {
+    HISTORY.lock().newest
+}
+
+pub fn get(n: u64) -> Option<Line> {
+    HISTORY.lock().get(n)
+}
+
+// Remember line. Blank lines, and a repeat of the line
+// before, aren't kept.
+pub fn add(line: &[u8]) {
+    if line.iter().all(|c| c.is_ascii_whitespace()) {
+        return;
+    }
+    let mut h = HISTORY.lock();
+    if h.get(h.newest).is_some_and(|last| last.as_bytes() == line) {
+        return;
+    }
+    h.newest += 1;
+    let slot = ((h.newest - 1) % NHISTORY as u64) as usize;
+    h.lines[slot] = Line::from(line);
+}
+
+// History expansion: !! is the last line, !n is line n.
+// Ok(None) if line doesn't start with !.
+pub fn expand(line: &[u8]) -> Result<Option<Line>, HistoryError> {
+    let Some(event) = line.strip_prefix(b"!") else {
+        return Ok(None);
+    };
+    let h = HISTORY.lock();
+    let n = if event == b"!" {
+        h.newest
+    } else {
+        core::str::from_utf8(event)
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .ok_or(HistoryError::NotFound)?
+    };
+    h.get(n).map(Some).ok_or(HistoryError::NotFound)
+}
+
+// the history command.
+pub fn print() {
+    for n in oldest()..=newest() {
+        if let Some(line) = get(n) {
+            println!("{:>5}  {}", n, line.as_str());
+        }
+    }
+}
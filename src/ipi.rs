@@ -0,0 +1,140 @@
+// Inter-processor interrupts (IPIs) and cross-calls.
+//
+// A hart interrupts another by writing 1 to the other hart's MSIP
+// register in the CLINT. That raises a machine-mode software
+// interrupt there, which timervec (trap.S) acknowledges and passes
+// on as a supervisor software interrupt; devintr() then calls
+// ipiintr().
+//
+// On top of that, call_on() and call_all() run a function on other
+// harts and wait for them to finish it. vm.rs uses this to flush
+// stale TLB entries on every hart after unmapping pages.
+use crate::config::NCPU;
+use crate::memlayout::CLINT_MSIP;
+use crate::percpu;
+use crate::proc;
+use crate::riscv;
+use crate::spinlock::{self, SpinLock};
+use core::fmt;
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// a function for other harts to run, given two arguments.
+pub type CallFn = fn(u64, u64);
+
+#[derive(Clone, Copy)]
+struct Call {
+    func: CallFn,
+    a: u64,
+    b: u64,
+}
+
+fn nop(_: u64, _: u64) {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IpiError {
+    // the hart doesn't exist, or hasn't started.
+    NotRunning(usize),
+}
+
+impl fmt::Display for IpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IpiError::NotRunning(hart) => write!(f, "hart {} is not running", hart),
+        }
+    }
+}
+
+// One cross-call runs at a time. The caller owns CALLING, puts the
+// call in CALL, then sets a bit in PENDING for each target hart;
+// each target runs the call and clears its bit.
+static CALLING: AtomicBool = AtomicBool::new(false);
+static CALL: SpinLock<Call> = SpinLock::new(Call { func: nop, a: 0, b: 0 }, "call");
+static PENDING: AtomicU64 = AtomicU64::new(0);
+
+// cross-calls each hart has run for others.
+percpu!(static CALLS_RUN: u64 = 0);
+
+pub fn calls_run() -> [u64; NCPU as usize] {
+    CALLS_RUN.all()
+}
+
+// Interrupt hart.
+pub fn send(hart: usize) {
+    if hart >= NCPU as usize {
+        panic!("ipi: bad hart {}", hart);
+    }
+    unsafe { write_volatile(CLINT_MSIP(hart as u64) as *mut u32, 1) };
+}
+
+// called from devintr() for every supervisor software interrupt;
+// run the cross-call, if one is waiting for this hart.
+pub fn ipiintr() {
+    let bit = 1 << proc::cpuid();
+    if PENDING.load(Ordering::Acquire) & bit == 0 {
+        return;
+    }
+    let call = *CALL.lock();
+    (call.func)(call.a, call.b);
+    CALLS_RUN.add(1);
+    PENDING.fetch_and(!bit, Ordering::Release);
+}
+
+// Run func(a, b) on each started hart in mask, and wait until all
+// of them have. Other harts run it from their interrupt handler,
+// and this one directly, all with interrupts off, so func must not
+// sleep or take locks that might be held with interrupts on.
+//
+// Interrupts must be on when calling other harts, so that this hart
+// keeps answering their calls while it waits for its own turn.
+pub fn call_mask(mask: u64, func: CallFn, a: u64, b: u64) {
+    spinlock::push_off();
+    let me = proc::cpuid();
+    let others = (0..NCPU as usize)
+        .filter(|&h| h != me && mask & (1 << h) != 0 && crate::hart_started(h as u64))
+        .fold(0, |m, h| m | (1 << h));
+    if mask & (1 << me) != 0 {
+        func(a, b);
+    }
+    spinlock::pop_off();
+
+    if others == 0 {
+        return;
+    }
+    if !riscv::intr_get() {
+        panic!("ipi: cross-call with interrupts off");
+    }
+
+    while CALLING
+        .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        core::hint::spin_loop();
+    }
+    *CALL.lock() = Call { func, a, b };
+    PENDING.store(others, Ordering::Release);
+    for hart in 0..NCPU as usize {
+        if others & (1 << hart) != 0 {
+            send(hart);
+        }
+    }
+    while PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+    CALLING.store(false, Ordering::Release);
+}
+
+// Run func(a, b) on hart and wait for it.
+pub fn call_on(hart: usize, func: CallFn, a: u64, b: u64) -> Result<(), IpiError> {
+    if hart >= NCPU as usize || !crate::hart_started(hart as u64) {
+        return Err(IpiError::NotRunning(hart));
+    }
+    call_mask(1 << hart, func, a, b);
+    Ok(())
+}
+
+// Run func(a, b) on every started hart, this one included,
+// and wait for all of them.
+pub fn call_all(func: CallFn, a: u64, b: u64) {
+    call_mask(u64::MAX, func, a, b);
+}
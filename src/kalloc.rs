@@ -1 +1,54 @@
+// Physical memory allocator, for kernel stacks, page-table pages,
+// and other kernel data structures. Allocates whole 4096-byte pages.
+// Based on kalloc.c from MIT 6.1810 (xv6).
+//
+// Pages come from the buddy allocator (buddy.rs) as order-0 blocks,
+// which also fills them with junk on free and on allocation.
+use crate::buddy;
+use crate::riscv::PGSIZE;
+use core::ptr;
 
+// Snapshot of the allocator counters, in pages.
+#[derive(Clone, Copy)]
+pub struct KmemStats {
+    pub total: u64,
+    pub free: u64,
+    pub used: u64,
+}
+
+// Set up the page allocator; buddy::init() does the work.
+pub fn kinit() {
+    buddy::init();
+}
+
+// Free the page of physical memory at pa,
+// which normally should have been returned by a
+// call to kalloc().
+pub fn kfree(pa: u64) {
+    buddy::free_pages(pa, 0);
+}
+
+// Allocate one 4096-byte page of physical memory.
+// Returns the physical address of the page, which the kernel
+// can use directly, or None if the memory cannot be allocated.
+pub fn kalloc() -> Option<u64> {
+    buddy::alloc_pages(0)
+}
+
+// Like kalloc(), but the page is zeroed.
+pub fn kzalloc() -> Option<u64> {
+    let pa = kalloc()?;
+    unsafe {
+        ptr::write_bytes(pa as *mut u8, 0, PGSIZE as usize);
+    }
+    Some(pa)
+}
+
+pub fn stats() -> KmemStats {
+    let s = buddy::stats();
+    KmemStats {
+        total: s.total_pages,
+        free: s.free_pages,
+        used: s.total_pages - s.free_pages,
+    }
+}
@@ -5,6 +5,10 @@
 #![allow(unused_macros)]
 #![allow(dead_code)]
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use config::NCPU;
+
+extern crate alloc;
 
 // Modules
 pub mod memlayout;
@@ -12,15 +16,34 @@ pub mod assembly;
 pub mod uart;
 pub mod riscv;
 pub mod vm;
+pub mod kalloc;
+pub mod buddy;
+pub mod heap;
+pub mod slab;
+pub mod config;
+pub mod proc;
+pub mod trap;
+pub mod timer;
+pub mod fdt;
+pub mod plic;
+pub mod console;
+pub mod history;
+pub mod monitor;
+pub mod spinlock;
+pub mod sleeplock;
+pub mod ipi;
 
 // boot.S jumps here after initializing the stack
 #[no_mangle]
 extern "C"
-fn start() {
+fn start(dtb: u64) {
+  // remember the devicetree, before anything needs to look at it.
+  fdt::init(dtb);
+
   // set M Previous Privilege mode to Supervisor, for mret.
   let mut x: u64 = riscv::r_mstatus();
-  x = x & !riscv::MSTATUS_MPP_MASK;
-  x = x | riscv::MSTATUS_MPP_S;
+  x &= !riscv::MSTATUS_MPP_MASK;
+  x |= riscv::MSTATUS_MPP_S;
   riscv::w_mstatus(x);
 
   // set M Exception Program Counter to main, for mret.
@@ -29,23 +52,30 @@ fn start() {
   // disable paging for now.
   riscv::w_satp(0);
 
-  // delegate all interrupts and exceptions to supervisor mode.
-  // riscv::w_medeleg(0xffff);
-  // riscv::w_mideleg(0xffff);
-  // riscv::w_sie(riscv::r_sie() | riscv::SIE_SEIE | riscv::SIE_STIE | riscv::SIE_SSIE);
+  // delegate all exceptions and supervisor interrupts to supervisor mode.
+  // an ecall from M-mode can't be delegated, and neither can the
+  // machine-mode interrupts (the CLINT timer and IPIs), which stay
+  // with the M-mode handler in trap.S.
+  riscv::w_medeleg(0xffff & !(1 << trap::EXC_ECALL_M));
+  riscv::w_mideleg(riscv::SIE_SEIE | riscv::SIE_STIE | riscv::SIE_SSIE);
+  riscv::w_sie(riscv::r_sie() | riscv::SIE_SEIE | riscv::SIE_STIE | riscv::SIE_SSIE);
 
   // configure Physical Memory Protection to give supervisor mode
   // access to all of physical memory.
   riscv::w_pmpaddr0(0x3fffffffffffff);
   riscv::w_pmpcfg0(0xf);
 
-  // ask for clock interrupts; TODO: set up later
-  timerinit();
+  // ask for clock interrupts.
+  timer::timerinit();
 
   // keep each CPU's hartid in its tp register, for cpuid().
   let id: u64 = riscv::r_mhartid();
   riscv::w_tp(id);
 
+  // point stvec at kernelvec, so that supervisor mode can
+  // take traps from its very first instruction.
+  trap::trapinithart();
+
   // switch to supervisor mode and jump to main().
   unsafe {
       asm!("mret");
@@ -53,82 +83,86 @@ fn start() {
 }
 
 
+// set by hart 0 once memory and devices are initialized.
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// which harts have come up.
+static HART_STARTED: [AtomicBool; NCPU as usize] = [const { AtomicBool::new(false) }; NCPU as usize];
+
+pub fn hart_started(hart: u64) -> bool {
+	HART_STARTED.get(hart as usize).is_some_and(|s| s.load(Ordering::Acquire))
+}
+
+// start() jumps here in supervisor mode on all CPUs.
 #[no_mangle]
 extern "C"
 fn kinit() {
-	let mut my_uart = uart::UartDriver::new(memlayout::UART0);
-	my_uart.init();
+	let hart = proc::cpuid();
+	if hart != 0 {
+		// wait for hart 0 to set up memory and devices.
+		while !STARTED.load(Ordering::Acquire) {
+			core::hint::spin_loop();
+		}
+		vm::kvminithart();    // turn on paging
+		plic::plicinithart(); // ask PLIC for device interrupts
+		HART_STARTED[hart].store(true, Ordering::Release);
+		println!("hart {} starting", hart);
+		proc::scheduler();
+	}
+
+	uart::uartinit();
+
+	let stacks = unsafe { memlayout::KERNEL_STACK_END - memlayout::KERNEL_STACK_START };
+	if stacks < NCPU as u64 * memlayout::BOOT_STACK_SIZE {
+		panic!("kinit: boot stacks too small for {} harts", NCPU);
+	}
+
+	kalloc::kinit();
+	heap::init();
+	buddy::print_stats();
+
+	// create the kernel page table and turn on paging
+	vm::kvminit();
+	vm::kvminithart();
+	proc::procinit();
+
+	// set up the interrupt controller; drivers register
+	// their interrupts with it as they come up.
+	plic::plicinit();
+	plic::plicinithart();
+
+	// let the other harts in.
+	HART_STARTED[0].store(true, Ordering::Release);
+	STARTED.store(true, Ordering::Release);
+
+	// take timer interrupts from here on.
+	riscv::intr_on();
+	timer::print_stats();
 
 	println!("Starting sh");
 
     vm::testing();
 
-	println!("mhartid: {}", riscv::r_mhartid());
+	println!("hartid: {}", proc::cpuid());
 
 	println!("sp: {}", riscv::r_sp());
-    // Test: transmission
-    print!("~ ");
-    loop {
-        if let Some(c) = my_uart.uart_getc() {
-            match c {
-                0x3 => {
-                    println!("");
-                    println!("^C: minux exiting");
-                    break
-                },
-                0x0D => { // ANSI for Enter
-                    println!("");
-                    print!("~ ");
-                },
-                0x1b => { // Arrow key control
-                    if let Some(next_byte) = my_uart.uart_getc() {
-                        if next_byte == 91 {
-                            // This is a right bracket! We're on our way!
-                            if let Some(b) = my_uart.uart_getc() {
-                                match b as char {
-                                    // eventually make up and down access the buffered history of
-                                    // commands
-                                    'A' => {
-                                        println!("up");
-                                    },
-                                    'B' => {
-                                        println!("down");
-                                    },
-                                    // likewise for each command, we need to keep track of the
-                                    // length so we do not go outside of bounds 
-                                    'C' => {
-                                        println!("right");
-                                    },
-                                    'D' => {
-                                        println!("left");
-                                    },
-                                    _ => {
-                                        print!("");
-                                    }
-                                }
-                            }
-                        }
-                    }
-                },
-                0x08 | 0x7F => { // ANSI for Delete
-                    print!("{}{}{}", '\u{0008}', ' ', '\u{0008}');
-                },
-                _ => {
-                    print!("{}", c as char)
-                },
-            }
-        }
-
-    }
+	if proc::kthread("monitor", shell).is_none() {
+		panic!("kinit: no process for the monitor");
+	}
+	proc::scheduler();
+}
+
+// the first process: the monitor, which runs until ^D.
+fn shell() {
+	monitor::run();
+	println!("^D: minux exiting");
 }
 
 #[macro_export]
 macro_rules! print
 {
 	($($args:tt)+) => ({
-        use core::fmt::Write;
-
-        let _ = write!(crate::uart::UartDriver::new(memlayout::UART0), $($args)+);
+		$crate::uart::print(format_args!($($args)+));
 	});
 }
 
@@ -152,6 +186,7 @@ macro_rules! println
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
+	uart::panicked();
 	print!("Aborting: ");
 	if let Some(p) = info.location() {
 		println!(
@@ -164,6 +199,9 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 	else {
 		println!("no information available.");
 	}
+	if let Some((size, align)) = heap::failed_layout() {
+		println!("last failed heap allocation: {} bytes, align {}", size, align);
+	}
 	abort();
 }
 
@@ -177,35 +215,4 @@ fn abort() -> ! {
 			asm!("wfi");
 		}
 	}
-}		
-
-#[no_mangle]
-fn timerinit() {
-//   // each CPU has a separate source of timer interrupts.
-//   let id: u64 = riscv::r_mhartid();
-    // ask the CLINT for a timer interrupt.
-//   let interval: u64 = 1000000; // cycles; about 1/10th second in qemu.
-//   *(uint64*)CLINT_MTIMECMP(id) = *(uint64*)CLINT_MTIME + interval;
- 
-    // prepare information in scratch[] for timervec.
-    // scratch[0..2] : space for timervec to save registers.
-    // scratch[3] : address of CLINT MTIMECMP register.
-    // scratch[4] : desired interval (in cycles) between timer interrupts.
-//   uint64 *scratch = &timer_scratch[id][0];
-//   scratch[3] = CLINT_MTIMECMP(id);
-//   scratch[4] = interval;
-//   riscv::w_mscratch(scratch as u64);
-// 
-//   // set the machine-mode trap handler.
-    let x: u64;
-    unsafe {
-        asm!("la {0}, asm_trap_vector ", out(reg) x);
-    }
-    riscv::w_mtvec(x);
-
-    // enable machine-mode interrupts.
-    // riscv::w_mstatus(riscv::r_mstatus() | riscv::MSTATUS_MIE);
- 
-    // enable machine-mode timer interrupts.
-    // riscv::w_mie(riscv::r_mie() | riscv::MIE_MTIE);
 }
@@ -2,6 +2,7 @@
 // qemu -machine virt is set up like this,
 // based on qemu's hw/riscv/virt.c
 // 00001000 -- boot ROM, provided by qemu 
+// 00100000 -- test device, for poweroff and reboot
 // 02000000 -- CLINT
 // 0C000000 -- PLIC
 // 10000000 -- uart0 
@@ -15,28 +16,65 @@
 // end -- start of kernel page allocation area
 // PHYSTOP -- end RAM used by the kernel
 
+use crate::config::KSTACK_PAGES;
+use crate::riscv::{MAXVA, PGSIZE};
+
+// qemu's test device (sifive,test0), which powers off or
+// resets the machine when one of these is written to it.
+pub const VIRT_TEST: u64 = 0x100000;
+pub const VIRT_TEST_SIZE: u64 = 0x1000;
+pub const VIRT_TEST_POWEROFF: u32 = 0x5555;
+pub const VIRT_TEST_REBOOT: u32 = 0x7777;
+
 // qemu puts UART registers here in physical memory.
 // IRQ: interrupt request
 pub const UART0: u64 = 0x10000000;
-pub const UART0_IRQ: usize = 10;
+pub const UART0_IRQ: u32 = 10;
+pub const UART0_SIZE: u64 = 0x1000;
 
 // virtio mmio interface
 pub const VIRTIO0: u64 = 0x10001000;
-pub const VIRTIO0_IRQ: usize = 1;
+pub const VIRTIO0_IRQ: u32 = 1;
+pub const VIRTIO0_SIZE: u64 = 0x1000;
 
-// core local interruptor (CLINT), which contains the timer.
+// core local interruptor (CLINT), which contains the timer
+// and the software interrupt (MSIP) registers.
 pub const CLINT: u64 =  0x2000000;
-// #define CLINT_MTIMECMP(hartid) (CLINT + 0x4000 + 8*(hartid))
-// - function?
+pub const CLINT_SIZE: u64 = 0x10000;
+#[allow(non_snake_case)]
+pub const fn CLINT_MSIP(hartid: u64) -> u64 {
+    CLINT + 4 * hartid
+}
+#[allow(non_snake_case)]
+pub const fn CLINT_MTIMECMP(hartid: u64) -> u64 {
+    CLINT + 0x4000 + 8 * hartid
+}
 pub const CLINT_MTIME: u64 = CLINT + 0xBFF8; // cycles since boot.
+// mtime ticks at the timebase-frequency from the device tree,
+// 10 MHz on qemu's virt machine.
+pub const CLINT_TIMEBASE_HZ: u64 = 10_000_000;
 
 // qemu puts platform-level interrupt controller (PLIC) here.
 pub const PLIC: u64 = 0x0c000000;
-pub const PLIC_PRIORITY: u64 = PLIC + 0x0;
+pub const PLIC_SIZE: u64 = 0x4000000;
+pub const PLIC_PRIORITY: u64 = PLIC;
 pub const PLIC_PENDING: u64 = PLIC + 0x1000;
-//#define PLIC_SENABLE(hart) (PLIC + 0x2080 + (hart)*0x100)
-//#define PLIC_SPRIORITY(hart) (PLIC + 0x201000 + (hart)*0x2000)
-//#define PLIC_SCLAIM(hart) (PLIC + 0x201004 + (hart)*0x2000)
+// qemu gives each hart a machine-mode and a supervisor-mode
+// context; these are the supervisor ones.
+#[allow(non_snake_case)]
+pub const fn PLIC_SENABLE(hart: u64) -> u64 {
+    PLIC + 0x2080 + hart * 0x100
+}
+#[allow(non_snake_case)]
+pub const fn PLIC_SPRIORITY(hart: u64) -> u64 {
+    PLIC + 0x201000 + hart * 0x2000
+}
+#[allow(non_snake_case)]
+pub const fn PLIC_SCLAIM(hart: u64) -> u64 {
+    PLIC + 0x201004 + hart * 0x2000
+}
+// interrupt sources the virt machine's PLIC implements, 1..=95.
+pub const PLIC_NSOURCES: usize = 96;
 
 // the kernel expects there to be RAM
 // for use by the kernel and user pages
@@ -44,13 +82,53 @@ pub const PLIC_PENDING: u64 = PLIC + 0x1000;
 pub const KERNBASE: u64 = 0x80000000;
 pub const PHYSTOP: u64 = KERNBASE + 128*1024*1024;
 
+// addresses from the linker script (virt.lds), exported by mem.S.
+// HEAP_START is the first byte after the boot stacks; buddy::init()
+// (buddy.rs) hands out the pages from there to MEMORY_END, less
+// the devicetree blob.
+extern "C" {
+    pub static HEAP_START: u64;
+    pub static HEAP_SIZE: u64;
+    pub static TEXT_START: u64;
+    pub static TEXT_END: u64;
+    pub static RODATA_START: u64;
+    pub static RODATA_END: u64;
+    pub static DATA_START: u64;
+    pub static DATA_END: u64;
+    pub static BSS_START: u64;
+    pub static BSS_END: u64;
+    pub static KERNEL_STACK_START: u64;
+    pub static KERNEL_STACK_END: u64;
+    pub static MEMORY_START: u64;
+    pub static MEMORY_END: u64;
+}
+
+// each hart's boot stack, between KERNEL_STACK_START and
+// KERNEL_STACK_END; hart n's is the (n+1)th from the bottom.
+pub const BOOT_STACK_SIZE: u64 = 0x20000;
+
 // map the trampoline page to the highest address,
 // in both user and kernel space.
-// #define TRAMPOLINE (MAXVA - PGSIZE)
+pub const TRAMPOLINE: u64 = MAXVA - PGSIZE;
 
 // map kernel stacks beneath the trampoline,
 // each surrounded by invalid guard pages.
-// #define KSTACK(p) (TRAMPOLINE - ((p)+1)* 2*PGSIZE)
+// process p's stack is KSTACK_PAGES pages starting at KSTACK(p),
+// and the page right below it, KSTACK_GUARD(p), is never mapped.
+pub const KSTACK_SIZE: u64 = KSTACK_PAGES as u64 * PGSIZE;
+// a kernel stack plus its guard page.
+pub const KSTACK_SLOT: u64 = KSTACK_SIZE + PGSIZE;
+
+#[allow(non_snake_case)]
+pub const fn KSTACK(p: usize) -> u64 {
+    TRAMPOLINE - (p as u64 + 1) * KSTACK_SLOT + PGSIZE
+}
+
+// the unmapped page just below process p's kernel stack.
+#[allow(non_snake_case)]
+pub const fn KSTACK_GUARD(p: usize) -> u64 {
+    KSTACK(p) - PGSIZE
+}
 
 // User memory layout.
 // Address zero first:
@@ -61,4 +139,4 @@ pub const PHYSTOP: u64 = KERNBASE + 128*1024*1024;
 //   ...
 //   TRAPFRAME (p->trapframe, used by the trampoline)
 //   TRAMPOLINE (the same page as in the kernel)
-// #define TRAPFRAME (TRAMPOLINE - PGSIZE)
+pub const TRAPFRAME: u64 = TRAMPOLINE - PGSIZE;
@@ -0,0 +1,515 @@
+// The kernel monitor: a command shell on the console for poking at
+// the machine before there is a user space.
+//
+// Each line is split on whitespace; the first word picks an entry in
+// COMMANDS and the rest are its arguments. !n and !! re-run lines
+// from the history (history.rs).
+use crate::config::NCPU;
+use crate::memlayout::{CLINT_TIMEBASE_HZ, VIRT_TEST, VIRT_TEST_POWEROFF, VIRT_TEST_REBOOT};
+use crate::riscv::{self, PTE_R, PTE_W};
+use crate::console::Completions;
+use crate::ipi::IpiError;
+use crate::uart::SerialPort;
+use crate::vm;
+use crate::{buddy, console, fdt, heap, history, ipi, kalloc, plic, proc, slab, timer, trap, uart};
+use crate::{print, println};
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
+
+// most words on a command line.
+const MAXARGS: usize = 8;
+
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(&[&str]) -> Result<(), MonitorError>,
+    // offers completions for argument number arg (from 0).
+    pub complete: Option<fn(arg: usize, out: &mut Completions)>,
+}
+
+pub static COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "[command]", help: "list commands, or describe one", run: help, complete: Some(complete_command) },
+    Command { name: "mem", usage: "", help: "page allocator and heap statistics", run: mem, complete: None },
+    Command { name: "regs", usage: "", help: "dump supervisor CSRs", run: regs, complete: None },
+    Command { name: "peek", usage: "[-b|-h|-w|-d] addr [count]", help: "read physical memory", run: peek, complete: Some(complete_width) },
+    Command { name: "poke", usage: "[-b|-h|-w|-d] addr value", help: "write physical memory", run: poke, complete: Some(complete_width) },
+    Command { name: "harts", usage: "", help: "list the harts", run: harts, complete: None },
+    Command { name: "ps", usage: "", help: "list processes", run: ps, complete: None },
+    Command { name: "ipi", usage: "[hart]", help: "time a cross-call to a hart, or to all", run: ipi_cmd, complete: None },
+    Command { name: "uptime", usage: "", help: "time since boot", run: uptime, complete: None },
+    Command { name: "timer", usage: "[hz]", help: "timer statistics, or set the tick rate", run: timer_cmd, complete: None },
+    Command { name: "irqs", usage: "", help: "interrupt counts", run: irqs, complete: None },
+    Command { name: "serial", usage: "[port]", help: "serial port settings and errors", run: serial, complete: Some(complete_port) },
+    Command { name: "history", usage: "", help: "list previous commands", run: history_cmd, complete: None },
+    Command { name: "reboot", usage: "", help: "restart the machine", run: reboot, complete: None },
+    Command { name: "poweroff", usage: "", help: "turn the machine off", run: poweroff, complete: None },
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MonitorError {
+    // wrong number or kind of arguments.
+    Usage,
+    // an argument that should have been a number.
+    BadNumber,
+    // the address isn't aligned for the access width.
+    Misaligned(u64),
+    // nothing is mapped at the address.
+    NotMapped(u64),
+    // the address is mapped, but not for this access.
+    NoAccess(u64),
+    // a cross-call couldn't be made.
+    Ipi(IpiError),
+}
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MonitorError::Usage => write!(f, "bad arguments"),
+            MonitorError::BadNumber => write!(f, "bad number"),
+            MonitorError::Misaligned(pa) => write!(f, "0x{:x} is misaligned", pa),
+            MonitorError::NotMapped(pa) => write!(f, "0x{:x} is not mapped", pa),
+            MonitorError::NoAccess(pa) => write!(f, "0x{:x} is protected", pa),
+            MonitorError::Ipi(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub fn lookup(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+// Read and run commands until end of file (control-d).
+pub fn run() {
+    let mut buf = [0u8; history::HISTORY_LINE];
+    console::set_completer(Some(complete));
+    loop {
+        console::prompt("~ ");
+        let n = console::read(&mut buf);
+        if console::take_interrupt() {
+            continue;
+        }
+        if n == 0 {
+            println!();
+            break;
+        }
+
+        let line = buf[..n].trim_ascii_end();
+        let cmd = match history::expand(line) {
+            Ok(Some(expanded)) => {
+                // show what !n or !! turned into.
+                println!("{}", expanded.as_str());
+                expanded
+            }
+            Ok(None) => history::Line::from(line),
+            Err(e) => {
+                println!("{}: {}", core::str::from_utf8(line).unwrap_or("?"), e);
+                continue;
+            }
+        };
+        history::add(cmd.as_bytes());
+        match core::str::from_utf8(cmd.as_bytes()) {
+            Ok(s) => runcmd(s),
+            Err(_) => println!("not text"),
+        }
+    }
+}
+
+// Run one command line.
+pub fn runcmd(line: &str) {
+    let mut argv = [""; MAXARGS];
+    let mut argc = 0;
+    for word in line.split_ascii_whitespace() {
+        if argc == MAXARGS {
+            println!("too many arguments");
+            return;
+        }
+        argv[argc] = word;
+        argc += 1;
+    }
+    if argc == 0 {
+        return;
+    }
+
+    let Some(cmd) = lookup(argv[0]) else {
+        println!("{}: unknown command (try help)", argv[0]);
+        return;
+    };
+    if let Err(e) = (cmd.run)(&argv[1..argc]) {
+        println!("{}: {}", cmd.name, e);
+        if e == MonitorError::Usage {
+            println!("usage: {} {}", cmd.name, cmd.usage);
+        }
+    }
+}
+
+// Tab completion: command names for the first word, then
+// whatever the command's own hook offers.
+fn complete(line: &str, out: &mut Completions) {
+    let mut words = line.split_ascii_whitespace();
+    // the word being completed, counting from 0; it's a new
+    // one if the line ends in a space.
+    let n = words.clone().count() - usize::from(!out.word().is_empty());
+    if n == 0 {
+        complete_command(0, out);
+        return;
+    }
+    let hook = words.next().and_then(lookup).and_then(|cmd| cmd.complete);
+    if let Some(hook) = hook {
+        hook(n - 1, out);
+    }
+}
+
+fn complete_command(arg: usize, out: &mut Completions) {
+    if arg == 0 {
+        for c in COMMANDS {
+            out.offer(c.name);
+        }
+    }
+}
+
+fn complete_width(arg: usize, out: &mut Completions) {
+    if arg == 0 {
+        for flag in ["-b", "-h", "-w", "-d"] {
+            out.offer(flag);
+        }
+    }
+}
+
+fn complete_port(arg: usize, out: &mut Completions) {
+    if arg == 0 {
+        for u in uart::ports().iter() {
+            out.offer(u.lock().name());
+        }
+    }
+}
+
+// a number in decimal, or hex with 0x. digits may be
+// separated with _, like 0x8000_0000.
+fn parse_num(s: &str) -> Result<u64, MonitorError> {
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+    let mut n: u64 = 0;
+    let mut any = false;
+    for c in digits.chars().filter(|&c| c != '_') {
+        let d = c.to_digit(radix).ok_or(MonitorError::BadNumber)?;
+        n = n
+            .checked_mul(radix as u64)
+            .and_then(|n| n.checked_add(d as u64))
+            .ok_or(MonitorError::BadNumber)?;
+        any = true;
+    }
+    if any {
+        Ok(n)
+    } else {
+        Err(MonitorError::BadNumber)
+    }
+}
+
+fn help(args: &[&str]) -> Result<(), MonitorError> {
+    match args {
+        [] => {
+            for c in COMMANDS {
+                println!("  {:<10} {}", c.name, c.help);
+            }
+            Ok(())
+        }
+        [name] => match lookup(name) {
+            Some(c) => {
+                println!("{} {}", c.name, c.usage);
+                println!("  {}", c.help);
+                Ok(())
+            }
+            None => {
+                println!("{}: unknown command", name);
+                Ok(())
+            }
+        },
+        _ => Err(MonitorError::Usage),
+    }
+}
+
+fn mem(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    let k = kalloc::stats();
+    println!("kalloc: {} pages, {} free, {} used", k.total, k.free, k.used);
+    buddy::print_stats();
+    heap::print_stats();
+    slab::print_stats();
+    Ok(())
+}
+
+fn regs(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    println!("hart {}  sp 0x{:016x}", proc::cpuid(), riscv::r_sp());
+    println!("sstatus  0x{:016x}  sie   0x{:016x}  sip   0x{:016x}", riscv::r_sstatus(), riscv::r_sie(), riscv::r_sip());
+    println!("sepc     0x{:016x}  scause 0x{:016x} stval 0x{:016x}", riscv::r_sepc(), riscv::r_scause(), riscv::r_stval());
+    println!("stvec    0x{:016x}  satp  0x{:016x}", riscv::r_stvec(), riscv::r_satp());
+    print!("time     0x{:016x}", riscv::r_time());
+    if timer::backend() == timer::Backend::Sstc {
+        print!("  stimecmp 0x{:016x}", riscv::r_stimecmp());
+    }
+    println!();
+    Ok(())
+}
+
+// access width in bytes from a leading -b/-h/-w/-d flag (8 if
+// none), and how many arguments the flag took up.
+fn width(args: &[&str]) -> (usize, usize) {
+    match args.first() {
+        Some(&"-b") => (1, 1),
+        Some(&"-h") => (2, 1),
+        Some(&"-w") => (4, 1),
+        Some(&"-d") => (8, 1),
+        _ => (8, 0),
+    }
+}
+
+// check that the kernel page table maps pa with perm.
+// the kernel direct-maps RAM and devices, so va == pa.
+fn check_access(pa: u64, size: usize, perm: u64) -> Result<(), MonitorError> {
+    if !pa.is_multiple_of(size as u64) {
+        return Err(MonitorError::Misaligned(pa));
+    }
+    let leaf = vm::with_kernel_pagetable(|kpgtbl| {
+        kpgtbl.walk(pa, false).ok().filter(|pte| pte.is_leaf()).map(|pte| pte.flags())
+    });
+    match leaf {
+        Some(flags) => {
+            if flags & perm == perm {
+                Ok(())
+            } else {
+                Err(MonitorError::NoAccess(pa))
+            }
+        }
+        None => Err(MonitorError::NotMapped(pa)),
+    }
+}
+
+fn peek(args: &[&str]) -> Result<(), MonitorError> {
+    let (size, skip) = width(args);
+    let (addr, count) = match &args[skip..] {
+        [addr] => (parse_num(addr)?, 1),
+        [addr, count] => (parse_num(addr)?, parse_num(count)?),
+        _ => return Err(MonitorError::Usage),
+    };
+    let per_line = 16 / size;
+    for i in 0..count {
+        let pa = addr.wrapping_add(i * size as u64);
+        check_access(pa, size, PTE_R)?;
+        if console::take_interrupt() {
+            break;
+        }
+        if i % per_line as u64 == 0 {
+            if i != 0 {
+                println!();
+            }
+            print!("0x{:x}:", pa);
+        }
+        let v = unsafe {
+            match size {
+                1 => read_volatile(pa as *const u8) as u64,
+                2 => read_volatile(pa as *const u16) as u64,
+                4 => read_volatile(pa as *const u32) as u64,
+                _ => read_volatile(pa as *const u64),
+            }
+        };
+        print!(" {:0width$x}", v, width = size * 2);
+    }
+    println!();
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), MonitorError> {
+    let (size, skip) = width(args);
+    let [addr, value] = &args[skip..] else {
+        return Err(MonitorError::Usage);
+    };
+    let (pa, v) = (parse_num(addr)?, parse_num(value)?);
+    if size < 8 && v >> (size * 8) != 0 {
+        return Err(MonitorError::BadNumber);
+    }
+    check_access(pa, size, PTE_R | PTE_W)?;
+    unsafe {
+        match size {
+            1 => write_volatile(pa as *mut u8, v as u8),
+            2 => write_volatile(pa as *mut u16, v as u16),
+            4 => write_volatile(pa as *mut u32, v as u32),
+            _ => write_volatile(pa as *mut u64, v),
+        }
+    }
+    Ok(())
+}
+
+fn harts(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    let me = proc::cpuid() as u64;
+    let intrs = trap::interrupts();
+    let calls = ipi::calls_run();
+    let Some(fdt) = fdt::get() else {
+        println!("hart {} (this one); no devicetree to list the others", me);
+        return Ok(());
+    };
+    for cpu in fdt.nodes().filter(|n| n.prop_str("device_type") == Some("cpu")) {
+        let Some((id, _)) = cpu.reg() else {
+            continue;
+        };
+        let state = if id >= NCPU as u64 {
+            "parked (beyond NCPU)"
+        } else if crate::hart_started(id) {
+            "running"
+        } else {
+            "not started"
+        };
+        print!(
+            "hart {}{}: {} {} {}",
+            id,
+            if id == me { "*" } else { " " },
+            cpu.prop_str("riscv,isa").unwrap_or("?"),
+            cpu.prop_str("status").unwrap_or("okay"),
+            state,
+        );
+        match (intrs.get(id as usize), calls.get(id as usize)) {
+            (Some(n), Some(c)) => println!(", {} interrupts, {} cross-calls", n, c),
+            _ => println!(),
+        }
+    }
+    Ok(())
+}
+
+fn ps(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    proc::procdump();
+    Ok(())
+}
+
+fn ipi_cmd(args: &[&str]) -> Result<(), MonitorError> {
+    fn nop(_: u64, _: u64) {}
+
+    let start = riscv::r_time();
+    match args {
+        [] => ipi::call_all(nop, 0, 0),
+        [hart] => {
+            ipi::call_on(parse_num(hart)? as usize, nop, 0, 0).map_err(MonitorError::Ipi)?;
+        }
+        _ => return Err(MonitorError::Usage),
+    }
+    let us = (riscv::r_time() - start) * 1_000_000 / CLINT_TIMEBASE_HZ;
+    println!("round trip {} us", us);
+    Ok(())
+}
+
+fn uptime(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    let ms = timer::uptime_ms();
+    println!(
+        "up {}.{:03} s, {} ticks at {} Hz",
+        ms / 1000,
+        ms % 1000,
+        timer::ticks(),
+        timer::tick_hz()
+    );
+    Ok(())
+}
+
+fn timer_cmd(args: &[&str]) -> Result<(), MonitorError> {
+    match args {
+        [] => timer::print_stats(),
+        [hz] => match parse_num(hz)? {
+            hz @ 1..=10_000 => timer::set_tick_hz(hz),
+            _ => return Err(MonitorError::BadNumber),
+        },
+        _ => return Err(MonitorError::Usage),
+    }
+    Ok(())
+}
+
+fn irqs(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    plic::print_stats();
+    Ok(())
+}
+
+fn serial(args: &[&str]) -> Result<(), MonitorError> {
+    let ports = uart::ports();
+    let show = match args {
+        [] => 0..ports.len(),
+        [name] => match ports.iter().position(|u| u.lock().name() == *name) {
+            Some(i) => i..i + 1,
+            None => {
+                println!("{}: no such port", name);
+                return Ok(());
+            }
+        },
+        _ => return Err(MonitorError::Usage),
+    };
+    for i in show {
+        // copy what to show, since printing takes the
+        // console's lock, which may be this port's.
+        let (name, base, irq, config, dropped, e) = {
+            let u = ports[i].lock();
+            (u.name(), u.base, u.irq, u.config(), u.rx_dropped(), u.line_errors())
+        };
+        print!("{} at 0x{:x} irq {}", name, base, irq);
+        if i == uart::console_index() {
+            print!(" (console)");
+        }
+        println!();
+        if let Some(c) = config {
+            println!(
+                "  {} baud, {} data bits, parity {:?}, stop bits {:?}, clock {} Hz",
+                c.baud, c.data_bits, c.parity, c.stop_bits, c.clock_hz
+            );
+        }
+        println!(
+            "  {} dropped, {} overrun, {} parity, {} framing, {} break",
+            dropped, e.overrun, e.parity, e.framing, e.brk
+        );
+    }
+    Ok(())
+}
+
+fn history_cmd(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    history::print();
+    Ok(())
+}
+
+fn reboot(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    println!("rebooting");
+    finish(VIRT_TEST_REBOOT)
+}
+
+fn poweroff(args: &[&str]) -> Result<(), MonitorError> {
+    if !args.is_empty() {
+        return Err(MonitorError::Usage);
+    }
+    println!("powering off");
+    finish(VIRT_TEST_POWEROFF)
+}
+
+// ask qemu's test device to stop or reset the machine.
+fn finish(code: u32) -> ! {
+    uart::panicked();
+    unsafe { write_volatile(VIRT_TEST as *mut u32, code) };
+    panic!("qemu test device didn't respond");
+}
@@ -0,0 +1,165 @@
+// the riscv Platform Level Interrupt Controller (PLIC).
+// Based on plic.c from MIT 6.1810 (xv6).
+//
+// Devices raise interrupts on numbered sources. A source reaches a
+// hart's supervisor context if it is enabled for that context and
+// its priority is above the context's threshold; the hart then
+// takes a supervisor external interrupt, claims the source,
+// handles it and completes it.
+//
+// Drivers attach a handler to their source with register(), and
+// devintr() in trap.rs calls intr() for every external interrupt.
+use crate::config::NCPU;
+use crate::memlayout::{PLIC_NSOURCES, PLIC_PRIORITY, PLIC_SCLAIM, PLIC_SENABLE, PLIC_SPRIORITY};
+use crate::proc;
+use crate::spinlock::SpinLock;
+use crate::{print, println};
+use core::ptr::{read_volatile, write_volatile};
+
+// highest priority the virt machine's PLIC supports.
+pub const MAX_PRIORITY: u32 = 7;
+
+#[derive(Clone, Copy)]
+struct Irq {
+    name: &'static str,
+    handler: Option<fn(u32)>,
+    count: u64,
+}
+
+static IRQS: SpinLock<[Irq; PLIC_NSOURCES]> = SpinLock::new(
+    [Irq {
+        name: "",
+        handler: None,
+        count: 0,
+    }; PLIC_NSOURCES],
+    "irqs",
+);
+
+fn reg(addr: u64) -> *mut u32 {
+    addr as *mut u32
+}
+
+fn check(irq: u32) {
+    if irq == 0 || irq as usize >= PLIC_NSOURCES {
+        panic!("plic: bad irq {}", irq);
+    }
+}
+
+// start with every source at priority 0, which never interrupts,
+// except those a driver has already registered.
+pub fn plicinit() {
+    let irqs = IRQS.lock();
+    for irq in 1..PLIC_NSOURCES as u32 {
+        if irqs[irq as usize].handler.is_none() {
+            set_priority(irq, 0);
+        }
+    }
+}
+
+// per-hart setup: accept any enabled source with
+// a priority above 0.
+pub fn plicinithart() {
+    let hart = proc::cpuid() as u64;
+    set_threshold(hart, 0);
+}
+
+pub fn set_priority(irq: u32, priority: u32) {
+    check(irq);
+    if priority > MAX_PRIORITY {
+        panic!("plic: bad priority {} for irq {}", priority, irq);
+    }
+    unsafe { write_volatile(reg(PLIC_PRIORITY + irq as u64 * 4), priority) };
+}
+
+// interrupts at or below threshold are masked for hart's
+// supervisor context.
+pub fn set_threshold(hart: u64, threshold: u32) {
+    unsafe { write_volatile(reg(PLIC_SPRIORITY(hart)), threshold) };
+}
+
+fn enable_bit(irq: u32, hart: u64) -> (*mut u32, u32) {
+    check(irq);
+    (reg(PLIC_SENABLE(hart) + (irq as u64 / 32) * 4), 1 << (irq % 32))
+}
+
+// let irq interrupt hart's supervisor context.
+pub fn enable(irq: u32, hart: u64) {
+    let (r, bit) = enable_bit(irq, hart);
+    unsafe { write_volatile(r, read_volatile(r) | bit) };
+}
+
+pub fn disable(irq: u32, hart: u64) {
+    let (r, bit) = enable_bit(irq, hart);
+    unsafe { write_volatile(r, read_volatile(r) & !bit) };
+}
+
+// Attach handler to irq, give it priority 1 and enable it
+// on every hart. The handler runs with interrupts off, between
+// claim and complete.
+pub fn register(irq: u32, name: &'static str, handler: fn(u32)) {
+    check(irq);
+    let mut irqs = IRQS.lock();
+    let entry = &mut irqs[irq as usize];
+    if entry.handler.is_some() {
+        panic!("plic: irq {} already registered to {}", irq, entry.name);
+    }
+    entry.name = name;
+    entry.handler = Some(handler);
+    set_priority(irq, 1);
+    for hart in 0..NCPU as u64 {
+        enable(irq, hart);
+    }
+}
+
+// ask the PLIC what interrupt we should serve.
+pub fn plic_claim() -> u32 {
+    let hart = proc::cpuid() as u64;
+    unsafe { read_volatile(reg(PLIC_SCLAIM(hart))) }
+}
+
+// tell the PLIC we've served this IRQ.
+pub fn plic_complete(irq: u32) {
+    let hart = proc::cpuid() as u64;
+    unsafe { write_volatile(reg(PLIC_SCLAIM(hart)), irq) };
+}
+
+// handle a supervisor external interrupt: claim the source,
+// run its handler, complete it. called from devintr().
+pub fn intr() {
+    // irq indicates which device interrupted.
+    let irq = plic_claim();
+
+    // the PLIC returns 0 if another hart claimed it first.
+    if irq == 0 {
+        return;
+    }
+
+    // don't hold the table while the handler runs.
+    let handler = {
+        let mut irqs = IRQS.lock();
+        let entry = &mut irqs[irq as usize];
+        if entry.handler.is_some() {
+            entry.count += 1;
+        }
+        entry.handler
+    };
+    match handler {
+        Some(handler) => handler(irq),
+        None => println!("unexpected interrupt irq={}", irq),
+    }
+
+    // the PLIC allows each device to raise at most one
+    // interrupt at a time; tell the PLIC the device is
+    // now allowed to interrupt again.
+    plic_complete(irq);
+}
+
+pub fn print_stats() {
+    let irqs = *IRQS.lock();
+    println!("plic: registered interrupts");
+    for (irq, entry) in irqs.iter().enumerate() {
+        if entry.handler.is_some() {
+            println!("  irq {:>3} {:<12} {}", irq, entry.name, entry.count);
+        }
+    }
+}
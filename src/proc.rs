@@ -0,0 +1,555 @@
+// Processes.
+// Based on proc.c from MIT 6.1810 (xv6).
+use crate::config::{KSTACK_PAGES, NCPU, NPROC};
+use crate::kalloc;
+use crate::memlayout::{KSTACK, KSTACK_GUARD, KSTACK_SIZE};
+use crate::riscv::{self, PGSIZE, PTE_R, PTE_W};
+use crate::spinlock::{self, SpinLock, SpinLockGuard};
+use crate::vm::{self, PageTable};
+use crate::{print, println};
+use core::cell::UnsafeCell;
+use core::ptr::{self, addr_of, addr_of_mut};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProcState {
+    Unused,
+    Used,
+    Sleeping,
+    Runnable,
+    Running,
+    Zombie,
+}
+
+impl ProcState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ProcState::Unused => "unused",
+            ProcState::Used => "used",
+            ProcState::Sleeping => "sleep",
+            ProcState::Runnable => "runble",
+            ProcState::Running => "run",
+            ProcState::Zombie => "zombie",
+        }
+    }
+}
+
+// What a process sleeps on: the address of whatever it is
+// waiting for, which its waker passes to wakeup().
+pub type Chan = usize;
+
+// The part of a process that its lock protects.
+#[derive(Clone, Copy)]
+pub struct ProcInner {
+    pub state: ProcState, // Process state
+    pub chan: Chan,       // If non-zero, sleeping on chan
+    pub pid: u64,         // Process ID
+    pub name: [u8; 16],   // Process name (debugging)
+    entry: Option<fn()>,  // what the kernel thread runs
+}
+
+impl ProcInner {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+}
+
+// Per-process state
+pub struct Proc {
+    pub inner: SpinLock<ProcInner>,
+    pub kstack: u64, // Virtual address of kernel stack
+    // swtch() here to run process. Only the hart holding
+    // inner, or running the process, touches it.
+    context: UnsafeCell<Context>,
+}
+
+// everything that changes is behind inner, but for the
+// context, which the lock's holder hands to swtch().
+unsafe impl Sync for Proc {}
+
+impl Proc {
+    const fn new(kstack: u64) -> Self {
+        Proc {
+            inner: SpinLock::new(
+                ProcInner {
+                    state: ProcState::Unused,
+                    chan: 0,
+                    pid: 0,
+                    name: [0; 16],
+                    entry: None,
+                },
+                "proc",
+            ),
+            kstack,
+            context: UnsafeCell::new(Context::new()),
+        }
+    }
+}
+
+static PROCS: [Proc; NPROC] = {
+    let mut procs = [const { Proc::new(0) }; NPROC];
+    let mut i = 0;
+    while i < NPROC {
+        procs[i].kstack = KSTACK(i);
+        i += 1;
+    }
+    procs
+};
+
+static NEXTPID: AtomicU64 = AtomicU64::new(1);
+
+extern "C" {
+    // in swtch.S.
+    fn swtch(old: *mut Context, new: *const Context);
+}
+
+// Saved registers for kernel context switches.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Context {
+    pub ra: u64,
+    pub sp: u64,
+
+    // callee-saved
+    pub s0: u64,
+    pub s1: u64,
+    pub s2: u64,
+    pub s3: u64,
+    pub s4: u64,
+    pub s5: u64,
+    pub s6: u64,
+    pub s7: u64,
+    pub s8: u64,
+    pub s9: u64,
+    pub s10: u64,
+    pub s11: u64,
+}
+
+impl Context {
+    pub const fn new() -> Self {
+        Context {
+            ra: 0,
+            sp: 0,
+            s0: 0,
+            s1: 0,
+            s2: 0,
+            s3: 0,
+            s4: 0,
+            s5: 0,
+            s6: 0,
+            s7: 0,
+            s8: 0,
+            s9: 0,
+            s10: 0,
+            s11: 0,
+        }
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context::new()
+    }
+}
+
+// Per-CPU state.
+pub struct Cpu {
+    pub proc: *const Proc, // The process running on this cpu, or null.
+    pub context: Context, // swtch() here to enter scheduler().
+    pub noff: u64,        // Depth of push_off() nesting.
+    pub intena: bool,     // Were interrupts enabled before push_off()?
+}
+
+static mut CPUS: [Cpu; NCPU as usize] = [const {
+    Cpu {
+        proc: ptr::null(),
+        context: Context::new(),
+        noff: 0,
+        intena: false,
+    }
+}; NCPU as usize];
+
+// Must be called with interrupts disabled,
+// to prevent race with process being moved
+// to a different CPU.
+pub fn cpuid() -> usize {
+    riscv::r_tp() as usize
+}
+
+// Return this CPU's cpu struct.
+// Interrupts must be disabled.
+pub fn mycpu() -> &'static mut Cpu {
+    #[cfg(debug_assertions)]
+    if riscv::intr_get() {
+        panic!("mycpu: interrupts on");
+    }
+    unsafe { &mut (*addr_of_mut!(CPUS))[cpuid()] }
+}
+
+// Could the caller, holding one spin lock and nothing else,
+// sleep() with it? Not in an interrupt handler, with more
+// locks held, or outside a process. Interrupts must be off.
+pub fn may_sleep() -> bool {
+    let c = mycpu();
+    !c.proc.is_null() && c.noff == 1 && c.intena
+}
+
+// Return the current process, or None if this
+// CPU is running its scheduler (or nothing yet).
+pub fn myproc() -> Option<&'static Proc> {
+    spinlock::push_off();
+    let p = mycpu().proc;
+    spinlock::pop_off();
+    unsafe { p.as_ref() }
+}
+
+// One T for each CPU, declared with percpu!. A CPU only touches its
+// own slot, and only with interrupts off, so that it can neither be
+// interrupted by a handler using the same slot nor moved to another
+// CPU halfway through; debug builds check this.
+pub struct PerCpu<T> {
+    name: &'static str,
+    slots: UnsafeCell<[T; NCPU as usize]>,
+}
+
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(name: &'static str, slots: [T; NCPU as usize]) -> Self {
+        PerCpu {
+            name,
+            slots: UnsafeCell::new(slots),
+        }
+    }
+
+    // Run f on this CPU's slot. Interrupts must be off.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        #[cfg(debug_assertions)]
+        if riscv::intr_get() {
+            panic!("percpu {}: interrupts on", self.name);
+        }
+        f(unsafe { &mut (*self.slots.get())[cpuid()] })
+    }
+}
+
+impl<T: Copy> PerCpu<T> {
+    // Every CPU's value, for statistics. Other CPUs' values
+    // may be a moment stale.
+    pub fn all(&self) -> [T; NCPU as usize] {
+        unsafe { ptr::read_volatile(self.slots.get()) }
+    }
+}
+
+impl PerCpu<u64> {
+    // Count n on this CPU. Interrupts must be off.
+    pub fn add(&self, n: u64) {
+        self.with(|c| *c += n);
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.all().iter().sum()
+    }
+}
+
+// Declare per-CPU statics:
+//   percpu!(pub static TICKS: u64 = 0);
+// gives every CPU its own TICKS, starting at 0.
+#[macro_export]
+macro_rules! percpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])*
+        $vis static $name: $crate::proc::PerCpu<$t> = $crate::proc::PerCpu::new(
+            stringify!($name),
+            [const { $init }; $crate::config::NCPU as usize],
+        );
+    };
+}
+
+pub fn procs() -> &'static [Proc; NPROC] {
+    &PROCS
+}
+
+// Make the page-table pages for every process's kernel stack,
+// high in memory, each above an invalid guard page. The stacks
+// themselves are mapped by kthread() and freed when the thread
+// exits, so that mapping one never needs to allocate.
+pub fn proc_mapstacks(kpgtbl: &mut PageTable) {
+    for p in 0..NPROC {
+        for i in 0..KSTACK_PAGES as u64 {
+            if let Err(e) = kpgtbl.walk(KSTACK(p) + i * PGSIZE, true) {
+                panic!("proc_mapstacks: {}", e);
+            }
+        }
+    }
+}
+
+// Allocate and map a kernel stack at va. false if there
+// is no memory for it.
+fn kstack_alloc(va: u64) -> bool {
+    let mut pages = [0u64; KSTACK_PAGES];
+    for i in 0..KSTACK_PAGES {
+        match kalloc::kalloc() {
+            Some(pa) => pages[i] = pa,
+            None => {
+                pages[..i].iter().for_each(|&pa| kalloc::kfree(pa));
+                return false;
+            }
+        }
+    }
+    vm::with_kernel_pagetable(|kpgtbl| {
+        for (i, &pa) in pages.iter().enumerate() {
+            let a = va + i as u64 * PGSIZE;
+            if let Err(e) = kpgtbl.map_pages(a, PGSIZE, pa, PTE_R | PTE_W) {
+                panic!("kstack_alloc: {}", e);
+            }
+        }
+    });
+    true
+}
+
+// initialize the proc table.
+pub fn procinit() {
+    for p in procs().iter() {
+        p.inner.lock().state = ProcState::Unused;
+    }
+}
+
+// Look in the process table for an UNUSED proc. If found, set it
+// up to run func as a kernel thread on its kernel stack, make it
+// runnable, and return its pid. None if there are no free procs,
+// or no memory for a kernel stack.
+// The thread exits when func returns.
+pub fn kthread(name: &str, func: fn()) -> Option<u64> {
+    for p in procs().iter() {
+        let mut inner = p.inner.lock();
+        if inner.state != ProcState::Unused {
+            continue;
+        }
+        if !kstack_alloc(p.kstack) {
+            return None;
+        }
+        inner.pid = NEXTPID.fetch_add(1, Ordering::Relaxed);
+        let len = name.len().min(inner.name.len() - 1);
+        inner.name = [0; 16];
+        inner.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        inner.entry = Some(func);
+
+        // Set up new context to start executing at kthread_start,
+        // on an empty kernel stack.
+        let context = unsafe { &mut *p.context.get() };
+        *context = Context::new();
+        context.ra = (kthread_start as *const ()) as u64;
+        context.sp = p.kstack + KSTACK_SIZE;
+
+        inner.chan = 0;
+        inner.state = ProcState::Runnable;
+        return Some(inner.pid);
+    }
+    None
+}
+
+// A kernel thread's very first scheduling by scheduler()
+// will swtch to kthread_start.
+fn kthread_start() -> ! {
+    let p = myproc().expect("kthread_start: no process");
+
+    // Still holding p.inner from scheduler.
+    unsafe { p.inner.force_unlock() };
+    // scheduler() runs with interrupts off.
+    riscv::intr_on();
+
+    let entry = p.inner.lock().entry;
+    if let Some(func) = entry {
+        func();
+    }
+    exit();
+}
+
+// Exit the current process. Does not return.
+// scheduler() frees the process once it is off its stack.
+pub fn exit() -> ! {
+    let p = myproc().expect("exit: no process");
+    let mut inner = p.inner.lock();
+    inner.state = ProcState::Zombie;
+    sched(&inner, p.context.get());
+    panic!("zombie exit");
+}
+
+// Per-CPU process scheduler.
+// Each CPU calls scheduler() after setting itself up.
+// Scheduler never returns.  It loops, doing:
+//  - choose a process to run.
+//  - swtch to start running that process.
+//  - eventually that process transfers control
+//    via swtch back to the scheduler.
+pub fn scheduler() -> ! {
+    riscv::intr_off();
+    mycpu().proc = ptr::null();
+    loop {
+        // The most recent process to run may have had interrupts
+        // turned off; enable them to avoid a deadlock if all
+        // processes are waiting. Then turn them back off
+        // to avoid a possible race between an interrupt
+        // and wfi.
+        riscv::intr_on();
+        riscv::intr_off();
+
+        let mut found = false;
+        for p in procs().iter() {
+            let mut inner = p.inner.lock();
+            if inner.state != ProcState::Runnable {
+                continue;
+            }
+            // Switch to chosen process.  It is the process's job
+            // to release its lock and then reacquire it
+            // before jumping back to us.
+            inner.state = ProcState::Running;
+            mycpu().proc = p;
+            unsafe { swtch(addr_of_mut!(mycpu().context), p.context.get()) };
+
+            // Process is done running for now.
+            mycpu().proc = ptr::null();
+            found = true;
+            if inner.state == ProcState::Zombie {
+                // it exited, and is off its stack now. free the
+                // stack with interrupts on and no locks held, so
+                // that the other harts can flush it from their
+                // TLBs, then the proc structure.
+                drop(inner);
+                riscv::intr_on();
+                if let Err(e) = vm::kvmunmap(p.kstack, KSTACK_PAGES as u64, true) {
+                    panic!("scheduler: freeing kstack: {}", e);
+                }
+                riscv::intr_off();
+
+                let mut inner = p.inner.lock();
+                inner.pid = 0;
+                inner.name = [0; 16];
+                inner.entry = None;
+                inner.chan = 0;
+                inner.state = ProcState::Unused;
+            }
+        }
+        if !found {
+            // nothing to run; stop running on this core until an interrupt.
+            riscv::wfi();
+        }
+    }
+}
+
+// Switch to scheduler.  Must hold only p.inner
+// and have changed proc's state. Saves and restores
+// intena because intena is a property of this
+// kernel thread, not this CPU. It should
+// be proc->intena and proc->noff, but that would
+// break in the few places where a lock is held but
+// there's no process.
+fn sched(inner: &SpinLockGuard<'_, ProcInner>, context: *mut Context) {
+    #[cfg(debug_assertions)]
+    if !inner.lock().holding() {
+        panic!("sched p->lock");
+    }
+    if mycpu().noff != 1 {
+        panic!("sched locks");
+    }
+    if inner.state == ProcState::Running {
+        panic!("sched running");
+    }
+    if riscv::intr_get() {
+        panic!("sched interruptible");
+    }
+
+    let intena = mycpu().intena;
+    unsafe { swtch(context, addr_of!(mycpu().context)) };
+    mycpu().intena = intena;
+}
+
+// Give up the CPU for one scheduling round.
+pub fn yield_() {
+    let Some(p) = myproc() else {
+        return;
+    };
+    let mut inner = p.inner.lock();
+    inner.state = ProcState::Runnable;
+    sched(&inner, p.context.get());
+}
+
+// Atomically release lock and sleep on chan.
+// Reacquires lock when awakened.
+// Without a process, as before the scheduler starts, waits
+// for an interrupt instead; then a wakeup() from another hart
+// may not be noticed until this hart's next tick. wfi returns
+// on a pending interrupt even with interrupts off, so they stay
+// off until pop_off() and the handler runs there, if at all.
+pub fn sleep<'a, T>(chan: Chan, guard: SpinLockGuard<'a, T>) -> SpinLockGuard<'a, T> {
+    let lock = guard.lock();
+    let Some(p) = myproc() else {
+        spinlock::push_off();
+        drop(guard);
+        riscv::wfi();
+        spinlock::pop_off();
+        return lock.lock();
+    };
+
+    // Must acquire p.inner in order to
+    // change p.state and then call sched.
+    // Once we hold p.inner, we can be
+    // guaranteed that we won't miss any wakeup
+    // (wakeup locks p.inner),
+    // so it's okay to release lock.
+    let mut inner = p.inner.lock();
+    drop(guard);
+
+    // Go to sleep.
+    inner.chan = chan;
+    inner.state = ProcState::Sleeping;
+
+    sched(&inner, p.context.get());
+
+    // Tidy up.
+    inner.chan = 0;
+
+    // Reacquire original lock.
+    drop(inner);
+    lock.lock()
+}
+
+// Wake up all processes sleeping on chan.
+// Must be called without any p.inner.
+pub fn wakeup(chan: Chan) {
+    let me = myproc().map_or(ptr::null(), |p| p as *const Proc);
+    for p in procs().iter() {
+        if ptr::eq(p, me) {
+            continue;
+        }
+        let mut inner = p.inner.lock();
+        if inner.state == ProcState::Sleeping && inner.chan == chan {
+            inner.state = ProcState::Runnable;
+        }
+    }
+}
+
+// Print a process listing to console, for the ps command.
+pub fn procdump() {
+    println!("{:>5} {:<7} {:<16} {}", "pid", "state", "name", "chan");
+    for p in procs().iter() {
+        // copy out under the lock; printing takes other locks.
+        let inner = *p.inner.lock();
+        if inner.state == ProcState::Unused {
+            continue;
+        }
+        print!("{:>5} {:<7} {:<16}", inner.pid, inner.state.name(), inner.name());
+        if inner.state == ProcState::Sleeping {
+            println!(" 0x{:x}", inner.chan);
+        } else {
+            println!();
+        }
+    }
+}
+
+// If va lies in the guard page below a kernel stack, return the
+// slot of the process whose stack overflowed into it.
+pub fn kstack_overflow(va: u64) -> Option<usize> {
+    (0..NPROC).find(|&p| va >= KSTACK_GUARD(p) && va < KSTACK_GUARD(p) + PGSIZE)
+}
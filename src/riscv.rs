@@ -38,6 +38,14 @@ pub fn w_mepc(x: u64) {
     }
 }
 
+pub fn r_mepc() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {0}, mepc", out(reg) x);
+    }
+    x
+}
+
 // Supervisor Status Register, sstatus
 pub const SSTATUS_SPP: u64 = 1 << 8;  // Previous mode, 1=Supervisor, 0=User
 pub const SSTATUS_SPIE: u64 = 1 << 5; // Supervisor Previous Interrupt Enable
@@ -60,6 +68,9 @@ pub fn w_sstatus(x: u64) {
 }
 
 // Supervisor Interrupt Pending
+pub const SIP_SSIP: u64 = 1 << 1; // software
+pub const SIP_STIP: u64 = 1 << 5; // timer
+
 pub fn r_sip() -> u64 {
     let x: u64;
     unsafe {
@@ -201,7 +212,7 @@ pub const SATP_SV39: u64 = 8 << 60;
 #[macro_export]
 macro_rules! MAKE_SATP{
     ($pagetable:expr) => {
-        SATP_SV39 | (($pagetable as u64) >> 12)
+        $crate::riscv::SATP_SV39 | (($pagetable as u64) >> 12)
     }
 }
 
@@ -221,48 +232,112 @@ pub fn r_satp() -> u64 {
     x
 }
 
+// Supervisor Scratch register, for early trap handler in trap.S.
+pub fn w_sscratch(x: u64) {
+    unsafe {
+        asm!("csrw sscratch, {0}", in(reg) x);
+    }
+}
+
 pub fn w_mscratch(x: u64) {
     unsafe {
-        asm!("csrw mstratch, {0}", in(reg) x);
+        asm!("csrw mscratch, {0}", in(reg) x);
     }
 }
 
 // Supervisor Trap Cause
-pub fn r_scause() {
+pub fn r_scause() -> u64 {
     let x: u64;
     unsafe {
         asm!("csrr {0}, scause", out(reg) x);
     }
+    x
 }
 
 // Supervisor Trap Value
-pub fn r_stval() {
+pub fn r_stval() -> u64 {
     let x: u64;
     unsafe {
         asm!("csrr {0}, stval", out(reg) x);
     }
+    x
+}
+
+// Machine Trap Cause
+pub fn r_mcause() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {0}, mcause", out(reg) x);
+    }
+    x
+}
+
+// Machine Trap Value
+pub fn r_mtval() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {0}, mtval", out(reg) x);
+    }
+    x
 }
 
 // Machine-mode Counter-Enable
+pub const MCOUNTEREN_TM: u64 = 1 << 1; // let lower modes read time
+
 pub fn w_mcounteren(x: u64) {
     unsafe {
         asm!("csrw mcounteren, {0}", in(reg) x);
     }
 }
 
-pub fn r_mcounteren() {
+pub fn r_mcounteren() -> u64 {
     let x: u64;
     unsafe {
         asm!("csrr {0}, mcounteren", out(reg) x);
     }
+    x
 }
 
 // machine-mode cycle counter
-pub fn r_time() {
+pub fn r_time() -> u64 {
     let x: u64;
     unsafe {
         asm!("csrr {0}, time", out(reg) x);
     }
+    x
+}
+
+// Supervisor Timer Compare (Sstc extension).
+// the assembler may not know these names, so use CSR numbers.
+pub fn r_stimecmp() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {0}, 0x14d", out(reg) x);
+    }
+    x
+}
+
+pub fn w_stimecmp(x: u64) {
+    unsafe {
+        asm!("csrw 0x14d, {0}", in(reg) x);
+    }
+}
+
+// Machine Environment Configuration
+pub const MENVCFG_STCE: u64 = 1 << 63; // enable stimecmp for supervisor mode
+
+pub fn r_menvcfg() -> u64 {
+    let x: u64;
+    unsafe {
+        asm!("csrr {0}, 0x30a", out(reg) x);
+    }
+    x
+}
+
+pub fn w_menvcfg(x: u64) {
+    unsafe {
+        asm!("csrw 0x30a, {0}", in(reg) x);
+    }
 }
 
 // enable device interrupts
@@ -280,6 +355,14 @@ pub fn intr_get() -> bool {
     (x & SSTATUS_SIE) != 0
 }
 
+// wait for an interrupt. returns once one is pending,
+// even if interrupts are off.
+pub fn wfi() {
+    unsafe {
+        asm!("wfi");
+    }
+}
+
 pub fn r_sp() -> u64 {
     let x: u64;
     unsafe {
@@ -291,11 +374,12 @@ pub fn r_sp() -> u64 {
 // read and write tp, the thread pointer, which xv6 uses to hold
 // this core's hartid (core number), the index into cpus[].
 // static inline uint64
-pub fn r_tp() {
-    let x: usize;
+pub fn r_tp() -> u64 {
+    let x: u64;
     unsafe {
         asm!("mv {0}, tp", out(reg) x);
     }
+    x
 }
 
 pub fn w_tp(x: u64) {
@@ -319,6 +403,13 @@ pub fn sfence_vma() {
     }
 }
 
+// flush the TLB entries for the page holding va.
+pub fn sfence_vma_va(va: u64) {
+    unsafe {
+        asm!("sfence.vma {0}, zero", in(reg) va);
+    }
+}
+
 /*
  * RISCV-64 PAGE TABLE DEFINITIONS
  */
@@ -333,7 +424,14 @@ pub const PGSHIFT: u64 = 12;  // bits of offset within a page
 #[macro_export]
 macro_rules! PGROUNDUP{
     ($pagetable:expr) => {
-        (($pagetable as u64) + PGSIZE - 1) & ~(PGSIZE-1)
+        (($pagetable as u64) + $crate::riscv::PGSIZE - 1) & !($crate::riscv::PGSIZE - 1)
+    }
+}
+
+#[macro_export]
+macro_rules! PGROUNDDOWN{
+    ($a:expr) => {
+        ($a as u64) & !($crate::riscv::PGSIZE - 1)
     }
 }
 
@@ -356,18 +454,21 @@ pub const PTE_X: u64 = 1 << 3;
 pub const PTE_U: u64 = 1 << 4; // user can access
  
 // shift a physical address to the right place for a PTE.
+#[macro_export]
 macro_rules! PA2PTE{
     ($pa:expr) => {
         (($pa as u64) >> 12) << 10
     }
 }
  
+#[macro_export]
 macro_rules! PTE2PA{
     ($pte:expr) => {
         (($pte as u64) >> 10) << 12
     }
 }
 
+#[macro_export]
 macro_rules! PTE_FLAGS{
     ($pte:expr) => {
         ($pte as u64) & 0x3FF
@@ -376,15 +477,17 @@ macro_rules! PTE_FLAGS{
  
 // extract the three 9-bit page table indices from a virtual address.
 pub const PXMASK: u64 = 0x1FF; // 9 bits
+#[macro_export]
 macro_rules! PXSHIFT{
     ($level:expr) => {
-        PGSHIFT+9*($level as u64) 
+        ($crate::riscv::PGSHIFT+9*($level as u64))
     }
 }
 
+#[macro_export]
 macro_rules! PX{
     ($level:expr, $va:expr) => {
-        (($va as u64) >> PXSHIFT!($level)) & PXMASK
+        (($va as u64) >> $crate::PXSHIFT!($level)) & $crate::riscv::PXMASK
     }
 }
 
@@ -0,0 +1,385 @@
+// Slab allocator: named caches of fixed-size kernel objects
+// (processes, files, pipe buffers, ...) layered on the buddy allocator.
+//
+// Each cache owns slabs, blocks of 2^order pages that begin with a
+// Slab header followed by equal-sized objects. Free objects inside a
+// slab are linked through their first word. Because buddy blocks are
+// aligned to their size, the slab owning an object is found by
+// rounding the object's address down to the slab size.
+//
+// In front of the slabs every CPU keeps a magazine, a small stack of
+// free objects that only that CPU touches, so the common alloc/free
+// path never has to go to the shared slab lists.
+use crate::buddy;
+use crate::config::NCPU;
+use crate::proc::PerCpu;
+use crate::riscv::PGSIZE;
+use crate::spinlock::{self, SpinLock};
+use crate::{print, println};
+use core::mem::size_of;
+use core::ptr;
+
+// objects held by each per-CPU magazine.
+const MAGAZINE_SIZE: usize = 16;
+// slabs are made large enough to hold at least this many objects.
+const MIN_OBJECTS_PER_SLAB: u64 = 8;
+// most caches that can be registered for statistics.
+const MAX_CACHES: usize = 32;
+
+// Every free object starts with a FreeObject, linking it to the
+// next free object of the same slab.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+// Header at the start of every slab.
+struct Slab {
+    next: *mut Slab,
+    prev: *mut Slab,
+    cache: *const KmemCache,
+    freelist: *mut FreeObject,
+    inuse: u64,
+}
+
+// A doubly-linked list of slabs.
+#[derive(Clone, Copy)]
+struct SlabList {
+    head: *mut Slab,
+    len: u64,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        SlabList { head: ptr::null_mut(), len: 0 }
+    }
+
+    fn push(&mut self, s: *mut Slab) {
+        unsafe {
+            (*s).prev = ptr::null_mut();
+            (*s).next = self.head;
+            if !self.head.is_null() {
+                (*self.head).prev = s;
+            }
+        }
+        self.head = s;
+        self.len += 1;
+    }
+
+    fn remove(&mut self, s: *mut Slab) {
+        unsafe {
+            if (*s).prev.is_null() {
+                self.head = (*s).next;
+            } else {
+                (*(*s).prev).next = (*s).next;
+            }
+            if !(*s).next.is_null() {
+                (*(*s).next).prev = (*s).prev;
+            }
+        }
+        self.len -= 1;
+    }
+}
+
+// The shared part of a cache.
+struct Slabs {
+    partial: SlabList, // slabs with both free and allocated objects
+    full: SlabList,    // slabs with no free objects
+    empty: SlabList,   // slabs with no allocated objects
+    order: usize,      // buddy order of each slab
+    first: u64,        // offset of the first object in a slab
+    per_slab: u64,     // objects in each slab
+    nslabs: u64,
+    inuse: u64,        // objects handed out of slabs, including magazines
+}
+
+// the slabs themselves are only reached through the lists.
+unsafe impl Send for Slabs {}
+
+// The per-CPU part of a cache.
+#[derive(Clone, Copy)]
+struct Magazine {
+    rounds: [*mut u8; MAGAZINE_SIZE],
+    n: usize,
+    allocs: u64,
+    frees: u64,
+}
+
+// the objects in a magazine belong to whichever CPU holds it.
+unsafe impl Send for Magazine {}
+
+// The slab lists are protected by the cache's lock, and each
+// magazine is only touched by its own CPU, with interrupts off.
+pub struct KmemCache {
+    name: &'static str,
+    size: u64,
+    align: u64,
+    slabs: SpinLock<Slabs>,
+    magazines: PerCpu<Magazine>,
+}
+
+// Snapshot of a cache, for the console.
+#[derive(Clone, Copy)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub size: u64,
+    pub slab_pages: u64,
+    pub slabs: u64,
+    pub objects: u64, // capacity of all slabs
+    pub inuse: u64,   // objects held by callers
+    pub cached: u64,  // free objects sitting in magazines
+    pub allocs: u64,
+    pub frees: u64,
+}
+
+impl KmemCache {
+    // A cache for objects of `size` bytes aligned to `align`, which
+    // must be a power of two. Usable in statics; see also create().
+    pub const fn new(name: &'static str, size: usize, align: usize) -> Self {
+        let align = if align < size_of::<usize>() {
+            size_of::<usize>()
+        } else {
+            align
+        };
+        let size = if size < size_of::<FreeObject>() {
+            size_of::<FreeObject>()
+        } else {
+            size
+        };
+        KmemCache {
+            name,
+            size: (size.div_ceil(align) * align) as u64,
+            align: align as u64,
+            slabs: SpinLock::new(
+                Slabs {
+                    partial: SlabList::new(),
+                    full: SlabList::new(),
+                    empty: SlabList::new(),
+                    order: 0,
+                    first: 0,
+                    per_slab: 0,
+                    nslabs: 0,
+                    inuse: 0,
+                },
+                name,
+            ),
+            magazines: PerCpu::new(
+                name,
+                [Magazine {
+                    rounds: [ptr::null_mut(); MAGAZINE_SIZE],
+                    n: 0,
+                    allocs: 0,
+                    frees: 0,
+                }; NCPU as usize],
+            ),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    // Allocate one object, or None if memory is exhausted.
+    pub fn alloc(&self) -> Option<*mut u8> {
+        spinlock::push_off();
+        let p = self.magazines.with(|m| {
+            if m.n == 0 {
+                // refill half the magazine from the slabs.
+                let mut s = self.slabs.lock();
+                while m.n < MAGAZINE_SIZE / 2 {
+                    match self.slab_alloc(&mut s) {
+                        Some(p) => {
+                            m.rounds[m.n] = p;
+                            m.n += 1;
+                        }
+                        None => break,
+                    }
+                }
+            }
+            if m.n > 0 {
+                m.n -= 1;
+                m.allocs += 1;
+                Some(m.rounds[m.n])
+            } else {
+                None
+            }
+        });
+        spinlock::pop_off();
+        p
+    }
+
+    // Return an object to the cache.
+    // obj must have come from alloc() on this cache and must not
+    // be used again.
+    pub fn free(&self, obj: *mut u8) {
+        spinlock::push_off();
+        self.magazines.with(|m| {
+            if m.n == MAGAZINE_SIZE {
+                // flush half the magazine back to the slabs.
+                let mut s = self.slabs.lock();
+                while m.n > MAGAZINE_SIZE / 2 {
+                    m.n -= 1;
+                    self.slab_free(&mut s, m.rounds[m.n]);
+                }
+            }
+            m.rounds[m.n] = obj;
+            m.n += 1;
+            m.frees += 1;
+        });
+        spinlock::pop_off();
+    }
+
+    // pick the slab size and layout on first use.
+    fn layout(&self, s: &mut Slabs) {
+        let header = size_of::<Slab>() as u64;
+        s.first = header.div_ceil(self.align) * self.align;
+        s.order = 0;
+        loop {
+            s.per_slab = ((PGSIZE << s.order) - s.first) / self.size;
+            if s.per_slab >= MIN_OBJECTS_PER_SLAB || s.order == buddy::MAX_ORDER {
+                break;
+            }
+            s.order += 1;
+        }
+        if s.per_slab == 0 {
+            panic!("kmem_cache {}: {} byte objects do not fit in a slab", self.name, self.size);
+        }
+    }
+
+    fn grow(&self, s: &mut Slabs) -> Option<*mut Slab> {
+        if s.per_slab == 0 {
+            self.layout(s);
+        }
+        let pa = buddy::alloc_pages(s.order)?;
+
+        let slab = pa as *mut Slab;
+        unsafe {
+            (*slab).cache = self;
+            (*slab).freelist = ptr::null_mut();
+            (*slab).inuse = 0;
+            for i in (0..s.per_slab).rev() {
+                let o = (pa + s.first + i * self.size) as *mut FreeObject;
+                (*o).next = (*slab).freelist;
+                (*slab).freelist = o;
+            }
+        }
+        s.nslabs += 1;
+        Some(slab)
+    }
+
+    fn slab_alloc(&self, s: &mut Slabs) -> Option<*mut u8> {
+        let slab = if !s.partial.head.is_null() {
+            let slab = s.partial.head;
+            s.partial.remove(slab);
+            slab
+        } else if !s.empty.head.is_null() {
+            let slab = s.empty.head;
+            s.empty.remove(slab);
+            slab
+        } else {
+            self.grow(s)?
+        };
+
+        let o = unsafe {
+            let o = (*slab).freelist;
+            (*slab).freelist = (*o).next;
+            (*slab).inuse += 1;
+            o
+        };
+        s.inuse += 1;
+
+        if unsafe { (*slab).freelist.is_null() } {
+            s.full.push(slab);
+        } else {
+            s.partial.push(slab);
+        }
+        Some(o as *mut u8)
+    }
+
+    fn slab_free(&self, s: &mut Slabs, obj: *mut u8) {
+        let slab = (obj as u64 & !((PGSIZE << s.order) - 1)) as *mut Slab;
+        if !ptr::eq(unsafe { (*slab).cache }, self) {
+            panic!("kmem_cache_free: {:p} does not belong to {}", obj, self.name);
+        }
+
+        unsafe {
+            if (*slab).freelist.is_null() {
+                s.full.remove(slab);
+            } else {
+                s.partial.remove(slab);
+            }
+            let o = obj as *mut FreeObject;
+            (*o).next = (*slab).freelist;
+            (*slab).freelist = o;
+            (*slab).inuse -= 1;
+        }
+        s.inuse -= 1;
+
+        if unsafe { (*slab).inuse } != 0 {
+            s.partial.push(slab);
+        } else if s.empty.len == 0 {
+            // keep one empty slab around to absorb alloc/free churn.
+            s.empty.push(slab);
+        } else {
+            s.nslabs -= 1;
+            buddy::free_pages(slab as u64, s.order);
+        }
+    }
+
+    // the magazine counts of other CPUs may be a moment stale.
+    pub fn stats(&self) -> CacheStats {
+        let s = self.slabs.lock();
+        let mut st = CacheStats {
+            name: self.name,
+            size: self.size,
+            slab_pages: 1 << s.order,
+            slabs: s.nslabs,
+            objects: s.nslabs * s.per_slab,
+            inuse: s.inuse,
+            cached: 0,
+            allocs: 0,
+            frees: 0,
+        };
+        for m in self.magazines.all().iter() {
+            st.cached += m.n as u64;
+            st.allocs += m.allocs;
+            st.frees += m.frees;
+        }
+        // a free on another CPU can move an object into its
+        // magazine between the two reads.
+        st.inuse = st.inuse.saturating_sub(st.cached);
+        st
+    }
+}
+
+// Every cache that wants to show up in print_stats().
+static CACHES: SpinLock<[Option<&'static KmemCache>; MAX_CACHES]> = SpinLock::new([None; MAX_CACHES], "caches");
+
+pub fn register(cache: &'static KmemCache) {
+    match CACHES.lock().iter_mut().find(|c| c.is_none()) {
+        Some(slot) => *slot = Some(cache),
+        None => panic!("kmem_cache {}: too many caches", cache.name),
+    }
+}
+
+// Create and register a named cache at run time.
+pub fn create(name: &'static str, size: usize, align: usize) -> &'static KmemCache {
+    let cache = alloc::boxed::Box::leak(alloc::boxed::Box::new(KmemCache::new(name, size, align)));
+    register(cache);
+    cache
+}
+
+pub fn print_stats() {
+    println!("slab: name             size  inuse/objects  slabs(pages)  cached  allocs  frees");
+    let caches = *CACHES.lock();
+    for cache in caches.iter().flatten() {
+        let s = cache.stats();
+        println!(
+            "  {:<16} {:>5} {:>6}/{:<7} {:>6}({:>2}) {:>7} {:>7} {:>6}",
+            s.name, s.size, s.inuse, s.objects, s.slabs, s.slab_pages, s.cached, s.allocs, s.frees
+        );
+    }
+}
@@ -0,0 +1,94 @@
+// Sleeping locks.
+// Based on sleeplock.c from MIT 6.1810 (xv6).
+//
+// A SleepLock<T> is for data that is held for a long time, such as
+// across disk I/O. A process waiting for it sleeps instead of
+// spinning, and the holder keeps interrupts on and may sleep itself,
+// neither of which is allowed with a SpinLock held. Only processes
+// should use them; an interrupt handler can't sleep.
+use crate::proc::{self, Chan};
+use crate::spinlock::SpinLock;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+struct Holder {
+    locked: bool, // Is the lock held?
+    pid: u64,     // Process holding lock
+}
+
+pub struct SleepLock<T> {
+    lk: SpinLock<Holder>, // spinlock protecting this sleep lock
+    name: &'static str,   // for debugging
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SleepLock<T> {}
+unsafe impl<T: Send> Send for SleepLock<T> {}
+
+pub struct SleepLockGuard<'a, T> {
+    lock: &'a SleepLock<T>,
+}
+
+impl<T> SleepLock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        SleepLock {
+            lk: SpinLock::new(Holder { locked: false, pid: 0 }, name),
+            name,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // what waiters sleep on.
+    fn chan(&self) -> Chan {
+        self as *const Self as Chan
+    }
+
+    // Acquire the lock, sleeping until it is free.
+    pub fn lock(&self) -> SleepLockGuard<'_, T> {
+        let mut h = self.lk.lock();
+        while h.locked {
+            h = proc::sleep(self.chan(), h);
+        }
+        h.locked = true;
+        h.pid = proc::myproc().map_or(0, |p| p.inner.lock().pid);
+        SleepLockGuard { lock: self }
+    }
+
+    // Release the lock; the guard's drop calls this.
+    fn unlock(&self) {
+        let mut h = self.lk.lock();
+        h.locked = false;
+        h.pid = 0;
+        proc::wakeup(self.chan());
+    }
+
+    // Is the current process holding the lock?
+    pub fn holding(&self) -> bool {
+        let h = self.lk.lock();
+        h.locked && h.pid == proc::myproc().map_or(0, |p| p.inner.lock().pid)
+    }
+}
+
+impl<T> Deref for SleepLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SleepLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SleepLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
@@ -0,0 +1,231 @@
+// Mutual exclusion spin locks.
+// Based on spinlock.c from MIT 6.1810 (xv6).
+//
+// A SpinLock<T> owns the data it protects. lock() spins until the
+// lock is free and returns a guard that derefs to the data and
+// releases the lock when it goes out of scope.
+//
+// A hart holding a spin lock keeps its interrupts off, so that an
+// interrupt handler can't spin forever on a lock its own hart holds.
+// push_off() and pop_off() count how deeply the hart has turned
+// interrupts off, in its Cpu (proc.rs), so that with two locks held
+// interrupts only come back on when both are released, and only if
+// they were on before the first.
+//
+// Debug builds also record which hart holds each lock and where it
+// was acquired: the pc the acquiring call returns to, and its source
+// line. They panic if a hart acquires a lock it already holds or
+// releases one it doesn't.
+use crate::proc::mycpu;
+use crate::riscv;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+#[cfg(debug_assertions)]
+use core::arch::asm;
+#[cfg(debug_assertions)]
+use core::panic::Location;
+#[cfg(debug_assertions)]
+use core::sync::atomic::AtomicU64;
+
+// holder of a lock nobody holds.
+#[cfg(debug_assertions)]
+const NOBODY: u64 = u64::MAX;
+
+// the acquire pc: the return address of the lock() call, read
+// before lock() makes any calls of its own.
+#[cfg(debug_assertions)]
+macro_rules! return_address {
+    () => {{
+        let ra: u64;
+        unsafe { asm!("mv {}, ra", out(reg) ra) };
+        ra
+    }};
+}
+
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    name: &'static str, // for debugging
+    #[cfg(debug_assertions)]
+    cpu: AtomicU64, // hartid of the holder
+    #[cfg(debug_assertions)]
+    pc: AtomicU64, // where the holder acquired it
+    #[cfg(debug_assertions)]
+    site: UnsafeCell<Option<&'static Location<'static>>>, // and in the source
+    data: UnsafeCell<T>,
+}
+
+// the lock hands the data to one hart at a time.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T, name: &'static str) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            name,
+            #[cfg(debug_assertions)]
+            cpu: AtomicU64::new(NOBODY),
+            #[cfg(debug_assertions)]
+            pc: AtomicU64::new(0),
+            #[cfg(debug_assertions)]
+            site: UnsafeCell::new(None),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // Acquire the lock.
+    // Loops (spins) until the lock is acquired.
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        let pc = return_address!();
+        push_off(); // disable interrupts to avoid deadlock.
+        #[cfg(debug_assertions)]
+        if self.holding() {
+            let held = self.pc.load(Ordering::Relaxed);
+            match unsafe { *self.site.get() } {
+                Some(site) => panic!("acquire {}: already held by this hart, since pc 0x{:x} ({})", self.name, held, site),
+                None => panic!("acquire {}: already held by this hart, since pc 0x{:x}", self.name, held),
+            }
+        }
+
+        // on RISC-V this is an amoswap or lr/sc loop.
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            core::hint::spin_loop();
+        }
+
+        #[cfg(debug_assertions)]
+        self.acquired(pc);
+        SpinLockGuard { lock: self }
+    }
+
+    // Acquire the lock if it is free; don't spin.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        #[cfg(debug_assertions)]
+        let pc = return_address!();
+        push_off();
+        if self
+            .locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            pop_off();
+            return None;
+        }
+        #[cfg(debug_assertions)]
+        self.acquired(pc);
+        Some(SpinLockGuard { lock: self })
+    }
+
+    // Record info about lock acquisition for holding() and debugging.
+    #[cfg(debug_assertions)]
+    #[track_caller]
+    fn acquired(&self, pc: u64) {
+        self.cpu.store(crate::proc::cpuid() as u64, Ordering::Relaxed);
+        self.pc.store(pc, Ordering::Relaxed);
+        unsafe { *self.site.get() = Some(Location::caller()) };
+    }
+
+    // Release the lock; the guard's drop calls this.
+    fn unlock(&self) {
+        #[cfg(debug_assertions)]
+        {
+            if !self.holding() {
+                panic!("release {}: not held by this hart", self.name);
+            }
+            self.cpu.store(NOBODY, Ordering::Relaxed);
+            self.pc.store(0, Ordering::Relaxed);
+            unsafe { *self.site.get() = None };
+        }
+
+        // the Release ordering makes the critical section's
+        // stores visible to the next holder before it sees
+        // the lock free.
+        self.locked.store(false, Ordering::Release);
+        pop_off();
+    }
+
+    // Release the lock without its guard, which is on another
+    // kernel stack: a new process releases the lock its CPU's
+    // scheduler() took before switching to it.
+    // The caller must know this hart holds the lock.
+    pub(crate) unsafe fn force_unlock(&self) {
+        self.unlock();
+    }
+
+    // Check whether this hart is holding the lock.
+    // Interrupts must be off.
+    #[cfg(debug_assertions)]
+    pub fn holding(&self) -> bool {
+        self.locked.load(Ordering::Relaxed) && self.cpu.load(Ordering::Relaxed) == crate::proc::cpuid() as u64
+    }
+}
+
+impl<'a, T> SpinLockGuard<'a, T> {
+    // the lock this guard holds.
+    pub fn lock(&self) -> &'a SpinLock<T> {
+        self.lock
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.unlock();
+    }
+}
+
+// push_off/pop_off are like intr_off()/intr_on() except that they are
+// matched: it takes two pop_off()s to undo two push_off()s. Also, if
+// interrupts are initially off, then push_off, pop_off leaves them off.
+pub fn push_off() {
+    let old = riscv::intr_get();
+
+    riscv::intr_off();
+    let c = mycpu();
+    if c.noff == 0 {
+        c.intena = old;
+    }
+    c.noff += 1;
+}
+
+pub fn pop_off() {
+    if riscv::intr_get() {
+        panic!("pop_off - interruptible");
+    }
+    let c = mycpu();
+    if c.noff < 1 {
+        panic!("pop_off");
+    }
+    c.noff -= 1;
+    if c.noff == 0 && c.intena {
+        riscv::intr_on();
+    }
+}
@@ -0,0 +1,285 @@
+// Timer interrupts.
+// Based on timerinit() in start.c and clockintr() in trap.c
+// from MIT 6.1810 (xv6).
+//
+// There are two ways to get a tick:
+//
+// Clint: each hart's CLINT raises a machine-mode timer interrupt when
+// mtime passes its mtimecmp. timervec (trap.S) takes it in machine
+// mode, pushes mtimecmp forward by one tick interval and raises a
+// supervisor software interrupt, which kerneltrap() hands to
+// clockintr().
+//
+// Sstc: with the Sstc extension, supervisor mode has its own
+// stimecmp CSR, which raises a supervisor timer interrupt directly,
+// and clockintr() moves it forward itself.
+//
+// timerinit() uses Sstc when the devicetree says the hart has it and
+// config::TIMER_PREFER_SSTC is set. Either way clockintr() records how
+// long after its deadline each tick arrived, per backend, so the two
+// can be compared.
+use crate::config::{NCPU, TICK_HZ, TIMER_PREFER_SSTC};
+use crate::fdt;
+use crate::memlayout::{CLINT_MSIP, CLINT_MTIME, CLINT_MTIMECMP, CLINT_TIMEBASE_HZ};
+use crate::proc;
+use crate::riscv;
+use crate::spinlock::SpinLock;
+use crate::{print, println};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+extern "C" {
+    // in trap.S, the machine-mode timer interrupt handler.
+    fn timervec();
+}
+
+// a scratch area per CPU for machine-mode timer interrupts.
+// scratch[0..2] : space for timervec to save registers.
+// scratch[3] : address of CLINT MTIMECMP register.
+// scratch[4] : desired interval (in cycles) between timer interrupts.
+// scratch[5] : the mtimecmp deadline timervec just handled.
+// scratch[6] : set by timervec when it passes a tick on.
+// scratch[7] : address of CLINT MSIP register, for IPIs.
+// the Sstc backend uses only scratch[4] and scratch[7].
+static mut TIMER_SCRATCH: [[u64; 8]; NCPU as usize] = [[0; 8]; NCPU as usize];
+
+// timer interrupts seen by hart 0 since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// current tick frequency, changed by set_tick_hz().
+static TICK_RATE: AtomicU64 = AtomicU64::new(TICK_HZ);
+
+fn interval(hz: u64) -> u64 {
+    CLINT_TIMEBASE_HZ / hz
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Backend {
+    Clint,
+    Sstc,
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Backend::Clint => "clint",
+            Backend::Sstc => "sstc",
+        }
+    }
+}
+
+// the backend each hart's timerinit() picked.
+static mut BACKEND: [Backend; NCPU as usize] = [Backend::Clint; NCPU as usize];
+
+// Interrupt latency: how many timebase cycles after its deadline
+// each tick reached clockintr().
+#[derive(Clone, Copy)]
+pub struct Latency {
+    pub count: u64,
+    pub total: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Latency {
+    const fn new() -> Self {
+        Latency {
+            count: 0,
+            total: 0,
+            min: u64::MAX,
+            max: 0,
+        }
+    }
+
+    fn record(&mut self, cycles: u64) {
+        self.count += 1;
+        self.total += cycles;
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+    }
+
+    fn merge(&mut self, other: &Latency) {
+        self.count += other.count;
+        self.total += other.total;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    pub fn mean(&self) -> u64 {
+        self.total.checked_div(self.count).unwrap_or(0)
+    }
+}
+
+// per hart, per backend; each hart only records its own.
+static LATENCY: [SpinLock<[Latency; 2]>; NCPU as usize] =
+    [const { SpinLock::new([Latency::new(); 2], "latency") }; NCPU as usize];
+
+// arrange to receive timer interrupts.
+// they will arrive in machine mode
+// at timervec in trap.S,
+// which turns them into software interrupts for
+// devintr() in trap.rs; or, with Sstc, straight
+// at kernelvec as supervisor timer interrupts.
+// runs in machine mode, from start().
+pub fn timerinit() {
+    // each CPU has a separate source of timer interrupts.
+    let id = riscv::r_mhartid();
+    let interval = interval(TICK_HZ);
+
+    // prepare information in scratch[] for timervec.
+    let scratch = unsafe { &mut (*addr_of_mut!(TIMER_SCRATCH))[id as usize] };
+    scratch[3] = CLINT_MTIMECMP(id);
+    scratch[4] = interval;
+    scratch[7] = CLINT_MSIP(id);
+    riscv::w_mscratch(scratch.as_mut_ptr() as u64);
+
+    // set the machine-mode trap handler. with Sstc it
+    // only sees IPIs and machine-mode faults.
+    riscv::w_mtvec((timervec as *const ()) as u64);
+
+    // take other harts' IPIs (ipi.rs), which arrive as
+    // machine-mode software interrupts.
+    riscv::w_mie(riscv::r_mie() | riscv::MIE_MSIE);
+
+    // let supervisor mode read the time CSR, for clockintr()
+    // and uptime_ms().
+    riscv::w_mcounteren(riscv::r_mcounteren() | riscv::MCOUNTEREN_TM);
+
+    if TIMER_PREFER_SSTC && has_sstc(id) {
+        // let supervisor mode use stimecmp, and ask for
+        // the first supervisor timer interrupt.
+        riscv::w_menvcfg(riscv::r_menvcfg() | riscv::MENVCFG_STCE);
+        riscv::w_stimecmp(riscv::r_time() + interval);
+        unsafe { (*addr_of_mut!(BACKEND))[id as usize] = Backend::Sstc };
+        return;
+    }
+
+    // ask the CLINT for a timer interrupt.
+    unsafe {
+        let now = read_volatile(CLINT_MTIME as *const u64);
+        write_volatile(CLINT_MTIMECMP(id) as *mut u64, now + interval);
+    }
+
+    // enable machine-mode interrupts.
+    riscv::w_mstatus(riscv::r_mstatus() | riscv::MSTATUS_MIE);
+
+    // enable machine-mode timer interrupts.
+    riscv::w_mie(riscv::r_mie() | riscv::MIE_MTIE);
+}
+
+// Does the devicetree say this hart implements Sstc?
+// newer devicetrees list extensions in riscv,isa-extensions;
+// older ones only have the riscv,isa string, where multi-letter
+// extensions follow the base ISA, separated by underscores.
+fn has_sstc(hartid: u64) -> bool {
+    let Some(fdt) = fdt::get() else {
+        return false;
+    };
+    let Some(cpu) = fdt
+        .nodes()
+        .filter(|n| n.prop_str("device_type") == Some("cpu"))
+        .find(|n| n.reg().map(|(reg, _)| reg) == Some(hartid))
+    else {
+        return false;
+    };
+    cpu.prop_strs("riscv,isa-extensions").any(|e| e == "sstc")
+        || cpu
+            .prop_str("riscv,isa")
+            .is_some_and(|isa| isa.split('_').skip(1).any(|e| e == "sstc"))
+}
+
+pub fn backend() -> Backend {
+    unsafe { (*addr_of!(BACKEND))[proc::cpuid()] }
+}
+
+// Change the tick frequency on every hart. Takes effect from
+// each hart's next timer interrupt.
+pub fn set_tick_hz(hz: u64) {
+    assert!(hz > 0 && hz <= CLINT_TIMEBASE_HZ, "set_tick_hz: bad frequency");
+    TICK_RATE.store(hz, Ordering::Relaxed);
+    for scratch in unsafe { (*addr_of_mut!(TIMER_SCRATCH)).iter_mut() } {
+        unsafe { write_volatile(&mut scratch[4], interval(hz)) };
+    }
+}
+
+pub fn tick_hz() -> u64 {
+    TICK_RATE.load(Ordering::Relaxed)
+}
+
+// called from devintr() for every timer interrupt, whichever
+// backend raised it.
+// only hart 0 counts ticks, so there is one clock for the
+// whole machine.
+pub fn clockintr() {
+    let now = riscv::r_time();
+    let hart = proc::cpuid();
+    let backend = backend();
+    let scratch = unsafe { &(*addr_of!(TIMER_SCRATCH))[hart] };
+
+    let deadline = match backend {
+        // timervec has already scheduled the next one.
+        Backend::Clint => unsafe { read_volatile(&scratch[5]) },
+        Backend::Sstc => {
+            // ask for the next timer interrupt. this also clears
+            // the interrupt request.
+            let deadline = riscv::r_stimecmp();
+            riscv::w_stimecmp(deadline + unsafe { read_volatile(&scratch[4]) });
+            deadline
+        }
+    };
+    LATENCY[hart].lock()[backend as usize].record(now.saturating_sub(deadline));
+
+    if hart == 0 {
+        TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Was the pending supervisor software interrupt a tick passed
+// on by timervec? Clears the flag. Interrupts must be off.
+pub fn take_tick() -> bool {
+    let scratch = unsafe { &mut (*addr_of_mut!(TIMER_SCRATCH))[proc::cpuid()] };
+    // an amoswap, which timervec can't interrupt halfway.
+    unsafe { AtomicU64::from_ptr(&mut scratch[6]) }.swap(0, Ordering::Relaxed) != 0
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// time since boot, from the CLINT's clock rather than the tick
+// count, so it stays right if the tick frequency changes.
+pub fn uptime_ms() -> u64 {
+    riscv::r_time() / (CLINT_TIMEBASE_HZ / 1000)
+}
+
+// Latency of one backend's ticks, over all harts.
+pub fn latency(backend: Backend) -> Latency {
+    let mut all = Latency::new();
+    for hart in LATENCY.iter() {
+        all.merge(&hart.lock()[backend as usize]);
+    }
+    all
+}
+
+// timebase cycles to nanoseconds.
+fn cycles_ns(cycles: u64) -> u64 {
+    cycles * 1_000_000_000 / CLINT_TIMEBASE_HZ
+}
+
+pub fn print_stats() {
+    println!("timer: {} backend, {} Hz, {} ticks", backend().name(), tick_hz(), ticks());
+    for b in [Backend::Clint, Backend::Sstc] {
+        let l = latency(b);
+        if l.count == 0 {
+            continue;
+        }
+        println!(
+            "  {}: {} interrupts, latency min {} ns, mean {} ns, max {} ns",
+            b.name(),
+            l.count,
+            cycles_ns(l.min),
+            cycles_ns(l.mean()),
+            cycles_ns(l.max)
+        );
+    }
+}
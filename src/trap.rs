@@ -0,0 +1,285 @@
+// Supervisor-mode traps.
+// Based on trap.c from MIT 6.1810 (xv6).
+use crate::config::NCPU;
+use crate::ipi;
+use crate::plic;
+use crate::proc;
+use crate::riscv::{self, SSTATUS_SPP};
+use crate::timer;
+use crate::uart;
+use crate::{percpu, print, println};
+use core::ptr::addr_of_mut;
+
+extern "C" {
+    // in trap.S, calls kerneltrap().
+    fn kernelvec();
+}
+
+// Registers saved by kernelvec; regs[n] holds register xn.
+#[repr(C)]
+pub struct TrapFrame {
+    pub regs: [u64; 32],
+}
+
+// ABI names of x0..x31, for register dumps.
+const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+impl TrapFrame {
+    pub fn print(&self) {
+        for (i, (name, reg)) in REG_NAMES.iter().zip(self.regs.iter()).enumerate() {
+            print!("{:>4}: 0x{:016x}", name, reg);
+            if i % 4 == 3 {
+                println!();
+            } else {
+                print!("  ");
+            }
+        }
+    }
+}
+
+// Per-hart scratch space for kernelvec: room to stash t1 and t2,
+// the interrupted sp, and the top of the hart's trap stack, which
+// kernelvec switches to when the frame would reach a guard page.
+#[repr(C)]
+struct TrapScratch {
+    t1: u64,
+    t2: u64,
+    sp: u64,
+    stack_top: u64,
+}
+
+const TRAP_STACK_SIZE: usize = 16384;
+
+#[repr(C, align(16))]
+struct TrapStack([u8; TRAP_STACK_SIZE]);
+
+static mut TRAP_SCRATCH: [TrapScratch; NCPU as usize] = [const {
+    TrapScratch {
+        t1: 0,
+        t2: 0,
+        sp: 0,
+        stack_top: 0,
+    }
+}; NCPU as usize];
+
+static mut TRAP_STACKS: [TrapStack; NCPU as usize] =
+    [const { TrapStack([0; TRAP_STACK_SIZE]) }; NCPU as usize];
+
+// scause values, with the interrupt bit clear.
+pub const EXC_INST_MISALIGNED: u64 = 0;
+pub const EXC_INST_ACCESS: u64 = 1;
+pub const EXC_ILLEGAL_INST: u64 = 2;
+pub const EXC_BREAKPOINT: u64 = 3;
+pub const EXC_LOAD_MISALIGNED: u64 = 4;
+pub const EXC_LOAD_ACCESS: u64 = 5;
+pub const EXC_STORE_MISALIGNED: u64 = 6;
+pub const EXC_STORE_ACCESS: u64 = 7;
+pub const EXC_ECALL_U: u64 = 8;
+pub const EXC_ECALL_S: u64 = 9;
+pub const EXC_ECALL_M: u64 = 11;
+pub const EXC_INST_PAGE_FAULT: u64 = 12;
+pub const EXC_LOAD_PAGE_FAULT: u64 = 13;
+pub const EXC_STORE_PAGE_FAULT: u64 = 15;
+
+// scause values, with the interrupt bit set.
+pub const INTR_S_SOFT: u64 = 1;
+pub const INTR_M_SOFT: u64 = 3;
+pub const INTR_S_TIMER: u64 = 5;
+pub const INTR_M_TIMER: u64 = 7;
+pub const INTR_S_EXTERNAL: u64 = 9;
+pub const INTR_M_EXTERNAL: u64 = 11;
+
+// the top bit of scause/mcause is set for interrupts.
+pub const CAUSE_INTERRUPT: u64 = 1 << 63;
+
+pub fn cause_name(cause: u64) -> &'static str {
+    if cause & CAUSE_INTERRUPT != 0 {
+        match cause & !CAUSE_INTERRUPT {
+            INTR_S_SOFT => "supervisor software interrupt",
+            INTR_M_SOFT => "machine software interrupt",
+            INTR_S_TIMER => "supervisor timer interrupt",
+            INTR_M_TIMER => "machine timer interrupt",
+            INTR_S_EXTERNAL => "supervisor external interrupt",
+            INTR_M_EXTERNAL => "machine external interrupt",
+            _ => "unknown interrupt",
+        }
+    } else {
+        match cause {
+            EXC_INST_MISALIGNED => "instruction address misaligned",
+            EXC_INST_ACCESS => "instruction access fault",
+            EXC_ILLEGAL_INST => "illegal instruction",
+            EXC_BREAKPOINT => "breakpoint",
+            EXC_LOAD_MISALIGNED => "load address misaligned",
+            EXC_LOAD_ACCESS => "load access fault",
+            EXC_STORE_MISALIGNED => "store address misaligned",
+            EXC_STORE_ACCESS => "store access fault",
+            EXC_ECALL_U => "environment call from U-mode",
+            EXC_ECALL_S => "environment call from S-mode",
+            EXC_ECALL_M => "environment call from M-mode",
+            EXC_INST_PAGE_FAULT => "instruction page fault",
+            EXC_LOAD_PAGE_FAULT => "load page fault",
+            EXC_STORE_PAGE_FAULT => "store page fault",
+            _ => "unknown exception",
+        }
+    }
+}
+
+// set up to take exceptions and traps while in the kernel.
+pub fn trapinithart() {
+    let hart = proc::cpuid();
+    unsafe {
+        let scratch = &mut (*addr_of_mut!(TRAP_SCRATCH))[hart];
+        let stack = addr_of_mut!(TRAP_STACKS[hart]) as u64;
+        scratch.stack_top = stack + TRAP_STACK_SIZE as u64;
+        riscv::w_sscratch(scratch as *mut TrapScratch as u64);
+    }
+    riscv::w_stvec((kernelvec as *const ()) as u64);
+}
+
+// interrupts and exceptions from kernel code go here via kernelvec,
+// on whatever the current kernel stack is.
+#[no_mangle]
+extern "C" fn kerneltrap(tf: &mut TrapFrame) {
+    let sepc = riscv::r_sepc();
+    let sstatus = riscv::r_sstatus();
+    let scause = riscv::r_scause();
+
+    if sstatus & SSTATUS_SPP == 0 {
+        panic!("kerneltrap: not from supervisor mode");
+    }
+    if riscv::intr_get() {
+        panic!("kerneltrap: interrupts enabled");
+    }
+
+    let which_dev = devintr(scause);
+    if which_dev == Intr::Unknown {
+        bad_trap(tf, scause, sepc, riscv::r_stval());
+    }
+
+    // give up the CPU if this is a timer interrupt, unless
+    // kernelvec put the frame on this hart's trap stack, which
+    // the next trap here would reuse.
+    if which_dev == Intr::Timer && !on_trap_stack(tf) && proc::myproc().is_some() {
+        proc::yield_();
+    }
+
+    // a yield may have caused some traps to occur,
+    // so restore trap registers for use by kernelvec's sret.
+    riscv::w_sepc(sepc);
+    riscv::w_sstatus(sstatus);
+}
+
+// Is tf on this hart's trap stack?
+fn on_trap_stack(tf: &TrapFrame) -> bool {
+    let stack = unsafe { addr_of_mut!(TRAP_STACKS[proc::cpuid()]) } as u64;
+    let a = tf as *const TrapFrame as u64;
+    a >= stack && a < stack + TRAP_STACK_SIZE as u64
+}
+
+// What devintr() found.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Intr {
+    Timer,
+    Device,
+    Unknown,
+}
+
+// interrupts each CPU has handled.
+percpu!(static INTERRUPTS: u64 = 0);
+
+pub fn interrupts() -> [u64; NCPU as usize] {
+    INTERRUPTS.all()
+}
+
+// check if it's an external interrupt or software interrupt,
+// and handle it.
+// returns Intr::Timer if timer interrupt,
+// Intr::Device if other device,
+// Intr::Unknown if not recognized.
+fn devintr(scause: u64) -> Intr {
+    let intr = if scause == CAUSE_INTERRUPT | INTR_S_EXTERNAL {
+        // this is a supervisor external interrupt, via PLIC.
+        plic::intr();
+
+        Intr::Device
+    } else if scause == CAUSE_INTERRUPT | INTR_S_TIMER {
+        // supervisor timer interrupt from stimecmp (Sstc);
+        // clockintr() sets the next deadline, which clears it.
+        timer::clockintr();
+
+        Intr::Timer
+    } else if scause == CAUSE_INTERRUPT | INTR_S_SOFT {
+        // software interrupt forwarded by timervec in trap.S,
+        // for a machine-mode timer interrupt, another hart's
+        // IPI, or both.
+
+        // acknowledge the software interrupt by clearing
+        // the SSIP bit in sip, before looking at why, so
+        // that another one arriving meanwhile isn't lost.
+        riscv::w_sip(riscv::r_sip() & !riscv::SIP_SSIP);
+
+        ipi::ipiintr();
+        if timer::take_tick() {
+            timer::clockintr();
+            Intr::Timer
+        } else {
+            Intr::Device
+        }
+    } else {
+        Intr::Unknown
+    };
+    if intr != Intr::Unknown {
+        INTERRUPTS.add(1);
+    }
+    intr
+}
+
+// Report a trap the kernel can't handle, then panic.
+fn bad_trap(tf: &TrapFrame, cause: u64, epc: u64, tval: u64) -> ! {
+    println!();
+    let is_fault = matches!(
+        cause,
+        EXC_INST_PAGE_FAULT | EXC_LOAD_PAGE_FAULT | EXC_STORE_PAGE_FAULT
+    );
+    if let Some(slot) = proc::kstack_overflow(tval).filter(|_| is_fault) {
+        // the overflowing process may hold its own lock.
+        match proc::procs()[slot].inner.try_lock().map(|inner| *inner) {
+            Some(p) => println!(
+                "kernel stack overflow in process slot {} ({}, pid {}), sp was 0x{:x}",
+                slot,
+                p.name(),
+                p.pid,
+                tf.regs[2]
+            ),
+            None => println!("kernel stack overflow in process slot {}, sp was 0x{:x}", slot, tf.regs[2]),
+        }
+    }
+    println!("{} at 0x{:x} from pc 0x{:x}", cause_name(cause), tval, epc);
+    println!("scause 0x{:x} sstatus 0x{:x} hart {}", cause, riscv::r_sstatus(), proc::cpuid());
+    tf.print();
+    panic!("kerneltrap");
+}
+
+// Every trap into machine mode lands here from asm_trap_vector in
+// trap.S. Report it and stop instead of restarting the kernel.
+#[no_mangle]
+extern "C" fn machinetrap() -> ! {
+    let mcause = riscv::r_mcause();
+    // the trap may have come before start() set tp, which
+    // printing needs, for cpuid(). then print without locks.
+    riscv::w_tp(riscv::r_mhartid());
+    uart::panicked();
+    println!();
+    println!(
+        "machine trap: {} at 0x{:x} from pc 0x{:x}",
+        cause_name(mcause),
+        riscv::r_mtval(),
+        riscv::r_mepc()
+    );
+    println!("mcause 0x{:x} mstatus 0x{:x} hart {}", mcause, riscv::r_mstatus(), riscv::r_mhartid());
+    panic!("machinetrap");
+}
@@ -1,29 +1,240 @@
-use core::fmt::{Write, Error};
+use core::fmt::{self, Write, Error};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::console;
+use crate::fdt;
+use crate::config::NUART;
+use crate::memlayout::{UART0, UART0_IRQ, UART0_SIZE};
+use crate::proc::{self, Chan};
+use crate::spinlock::{self, SpinLock};
+use crate::{plic, riscv};
+use crate::{print, println};
 // Information about the NS16550A UART chipset: http://byterunner.com/16550.html
 
 // Useful constants for working with the NS16550A UART chip
 const RHR: u8 = 0;                  // receive holding register (for input bytes)
 const THR: u8 = 0;                 // transmit holding register (for output bytes) 
+const DLL: u8 = 0;                 // divisor latch, low byte (when LCR_BAUD_LATCH is set)
 const IER: u8 = 1;                 // interrupt enable register
+const DLM: u8 = 1;                 // divisor latch, high byte (when LCR_BAUD_LATCH is set)
 const IER_RX_ENABLE: u8 = 1<<0;
 const IER_TX_ENABLE: u8 = 1<<1;
+const IER_LINE_STATUS_ENABLE: u8 = 1<<2; // interrupt on receive errors
 const FCR: u8 = 2;                 // FIFO control register
 const FCR_FIFO_ENABLE: u8 = 1<<0;
 const FCR_FIFO_CLEAR: u8 = 3<<1; // clear the content of the two FIFOs
+const FCR_TRIGGER_SHIFT: u8 = 6; // receive FIFO interrupt trigger level, bits 6-7
 const ISR: u8 = 2;                 // interrupt status register
 const LCR: u8 = 3;                 // line control register
-const LCR_WORD_LEN_5: u8 = 1<<0 | 1<<1;
-const LCR_EIGHT_BITS: u8 = 3<<0;
+const LCR_WORD_LEN_SHIFT: u8 = 0; // word length - 5, bits 0-1
+const LCR_STOP_BITS_2: u8 = 1<<2; // 2 stop bits (1.5 for 5-bit words)
+const LCR_PARITY_ENABLE: u8 = 1<<3;
+const LCR_PARITY_EVEN: u8 = 1<<4;
+const LCR_PARITY_STICK: u8 = 1<<5; // parity bit is always 1 (odd) or 0 (even)
 const LCR_BAUD_LATCH: u8 = 1<<7; // special mode to set baud rate
 const LSR: u8 = 5;                 // line status register
 const LSR_RX_READY: u8 = 1<<0;   // input is waiting to be read from RHR
+const LSR_OVERRUN: u8 = 1<<1;    // a received byte was lost
+const LSR_PARITY: u8 = 1<<2;     // the byte in RHR has bad parity
+const LSR_FRAMING: u8 = 1<<3;    // the byte in RHR has no valid stop bit
+const LSR_BREAK: u8 = 1<<4;      // the line was held low for a whole word
 const LSR_TX_IDLE: u8 = 1<<5;    // THR can accept another character to send
-                                                 
+
+// qemu's ns16550a runs from a 3.6864 MHz clock, which is also
+// what its devicetree node says in clock-frequency.
+pub const UART_DEFAULT_CLOCK_HZ: u32 = 3_686_400;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+    Mark,  // always 1
+    Space, // always 0
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+// how many bytes the receive FIFO holds before it interrupts.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FifoTrigger {
+    One = 0,
+    Four = 1,
+    Eight = 2,
+    Fourteen = 3,
+}
+
+// Line settings for a UART.
+#[derive(Clone, Copy, Debug)]
+pub struct UartConfig {
+    pub baud: u32,
+    pub data_bits: u8, // 5 to 8
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub fifo_trigger: FifoTrigger,
+    pub clock_hz: u32, // the chip's input clock
+}
+
+impl Default for UartConfig {
+    // 115200 baud, 8N1.
+    fn default() -> Self {
+        UartConfig {
+            baud: 115200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            fifo_trigger: FifoTrigger::One,
+            clock_hz: UART_DEFAULT_CLOCK_HZ,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UartError {
+    // the baud rate can't be reached from the input clock.
+    BadBaud(u32),
+    // data_bits isn't 5 to 8.
+    BadDataBits(u8),
+}
+
+impl fmt::Display for UartError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UartError::BadBaud(baud) => write!(f, "can't run at {} baud", baud),
+            UartError::BadDataBits(bits) => write!(f, "can't send {}-bit words", bits),
+        }
+    }
+}
+
+impl UartConfig {
+    // The config with the input clock the devicetree gives
+    // for the UART at base, if it gives one.
+    pub fn for_uart(base: u64) -> Self {
+        let mut config = UartConfig::default();
+        let clock = fdt::get().and_then(|fdt| {
+            fdt.compatible("ns16550a")
+                .find(|n| n.reg().map(|(addr, _)| addr) == Some(base))
+                .and_then(|n| n.prop_u32("clock-frequency"))
+        });
+        if let Some(hz) = clock {
+            config.clock_hz = hz;
+        }
+        config
+    }
+
+    // The NS16550A divides its input clock by 16 * divisor:
+    // divisor = clock_hz / (baud * 16), rounded to nearest.
+    pub fn divisor(&self) -> Result<u16, UartError> {
+        let div = match (self.baud as u64).checked_mul(16) {
+            Some(d) if d > 0 => (self.clock_hz as u64 + d / 2) / d,
+            _ => 0,
+        };
+        match u16::try_from(div) {
+            Ok(div) if div > 0 => Ok(div),
+            _ => Err(UartError::BadBaud(self.baud)),
+        }
+    }
+
+    fn lcr(&self) -> Result<u8, UartError> {
+        if !(5..=8).contains(&self.data_bits) {
+            return Err(UartError::BadDataBits(self.data_bits));
+        }
+        let mut lcr = (self.data_bits - 5) << LCR_WORD_LEN_SHIFT;
+        if self.stop_bits == StopBits::Two {
+            lcr |= LCR_STOP_BITS_2;
+        }
+        lcr |= match self.parity {
+            Parity::None => 0,
+            Parity::Odd => LCR_PARITY_ENABLE,
+            Parity::Even => LCR_PARITY_ENABLE | LCR_PARITY_EVEN,
+            Parity::Mark => LCR_PARITY_ENABLE | LCR_PARITY_STICK,
+            Parity::Space => LCR_PARITY_ENABLE | LCR_PARITY_EVEN | LCR_PARITY_STICK,
+        };
+        Ok(lcr)
+    }
+}
+
+// Receive errors seen in LSR, counted per port.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct LineErrors {
+    pub overrun: u64,
+    pub parity: u64,
+    pub framing: u64,
+    pub brk: u64,
+}
+
+impl LineErrors {
+    const fn new() -> Self {
+        LineErrors { overrun: 0, parity: 0, framing: 0, brk: 0 }
+    }
+
+    fn record(&mut self, lsr: u8) {
+        if lsr & LSR_OVERRUN != 0 {
+            self.overrun += 1;
+        }
+        if lsr & LSR_PARITY != 0 {
+            self.parity += 1;
+        }
+        if lsr & LSR_FRAMING != 0 {
+            self.framing += 1;
+        }
+        if lsr & LSR_BREAK != 0 {
+            self.brk += 1;
+        }
+    }
+}
+
 pub struct UartDriver {
     base_address: u64,
 }
 
-const UART_TX_BUF_SIZE: u8 = 32;
+const UART_TX_BUF_SIZE: usize = 32;
+const UART_RX_BUF_SIZE: usize = 128;
+
+// A byte ring buffer. r and w only ever grow; the next byte
+// to read is at buf[r % N], the next free slot at buf[w % N].
+struct Ring<const N: usize> {
+    buf: [u8; N],
+    r: u64,
+    w: u64,
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        Ring { buf: [0; N], r: 0, w: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.r == self.w
+    }
+
+    fn is_full(&self) -> bool {
+        self.w == self.r + N as u64
+    }
+
+    // how many more bytes fit.
+    fn room(&self) -> usize {
+        N - (self.w - self.r) as usize
+    }
+
+    fn push(&mut self, c: u8) {
+        self.buf[(self.w % N as u64) as usize] = c;
+        self.w += 1;
+    }
+
+    fn pop(&mut self) -> u8 {
+        let c = self.buf[(self.r % N as u64) as usize];
+        self.r += 1;
+        c
+    }
+}
+
+// set by panicked(); from then on console output bypasses
+// the buffer.
+static PANICKED: AtomicBool = AtomicBool::new(false);
 
 
 impl UartDriver {
@@ -33,48 +244,33 @@ impl UartDriver {
         }
     }
 
-    // TODO: research BAUD rate
-	pub fn init(&mut self) {
+    // Program the line settings and FIFOs, and turn on
+    // receive, transmit and line-status interrupts.
+	pub fn init(&mut self, config: &UartConfig) -> Result<(), UartError> {
+		let divisor = config.divisor()?;
+		let lcr = config.lcr()?;
 		unsafe {
-			// Set the word length for LCR
-			self.getreg(LCR).write_volatile(LCR_WORD_LEN_5);
-
-			// Enable the FIFO
-			self.getreg(FCR).write_volatile(FCR_FIFO_ENABLE);
-
-			// Enable receiver buffer interrupts            
-            self.getreg(IER).write_volatile(IER_TX_ENABLE | IER_RX_ENABLE);
-
-			// If we cared about the divisor, the code below would set the divisor
-			// from a global clock rate of 22.729 MHz (22,729,000 cycles per second)
-			// to a signaling rate of 2400 (BAUD). We usually have much faster signalling
-			// rates nowadays, but this demonstrates what the divisor actually does.
-			// The formula given in the NS16500A specification for calculating the divisor
-			// is:
-			// divisor = ceil( (clock_hz) / (baud_sps x 16) )
-			// So, we substitute our values and get:
-			// divisor = ceil( 22_729_000 / (2400 x 16) )
-			// divisor = ceil( 22_729_000 / 38_400 )
-			// divisor = ceil( 591.901 ) = 592
-			// The divisor register is two bytes (16 bits), so we need to split the value
-			// 592 into two bytes. Typically, we would calculate this based on measuring
-			// the clock rate, but again, for our purposes [qemu], this doesn't really do
-			// anything.
-			let divisor: u16 = 592;
-			let divisor_least: u8 = (divisor & 0xff).try_into().unwrap();
-			let divisor_most:  u8 = (divisor >> 8).try_into().unwrap();
-
-            // Enable internal baud rate counter latch (DLAB: Divisor Latch Access Bit)
-	    	self.getreg(LCR).write_volatile(self.getreg(LCR).read_volatile() | LCR_BAUD_LATCH);
-
-            // When the DLAB is enabled, the THR becomes the LSB (least significant bit) of the Divisor Latch 
-            // and the IER becomes the MSB (most significant bit) of the Divisor Latch
-			self.getreg(THR).write_volatile(divisor_least);
-			self.getreg(IER).write_volatile(divisor_most);
-
-			// Disable internal baud rate counter latch, to get access to RHR, THR and IER
-			self.getreg(LCR).write_volatile(self.getreg(LCR).read_volatile() & !LCR_BAUD_LATCH);
+			// disable interrupts.
+			self.getreg(IER).write_volatile(0);
+
+			// special mode to set baud rate. while the latch is set,
+			// RHR/THR and IER are the low and high bytes of the divisor.
+			self.getreg(LCR).write_volatile(LCR_BAUD_LATCH);
+			self.getreg(DLL).write_volatile((divisor & 0xff) as u8);
+			self.getreg(DLM).write_volatile((divisor >> 8) as u8);
+
+			// leave set-baud mode, and set the word format.
+			self.getreg(LCR).write_volatile(lcr);
+
+			// reset and enable FIFOs.
+			self.getreg(FCR).write_volatile(
+				FCR_FIFO_ENABLE | FCR_FIFO_CLEAR | (config.fifo_trigger as u8) << FCR_TRIGGER_SHIFT,
+			);
+
+			// enable transmit, receive and line status interrupts.
+			self.getreg(IER).write_volatile(IER_TX_ENABLE | IER_RX_ENABLE | IER_LINE_STATUS_ENABLE);
 		}
+		Ok(())
 	}
 
     // #define Reg(index) ((volatile unsigned char *)(UART0 + reg))
@@ -90,6 +286,11 @@ impl UartDriver {
         }
     }
 
+    // read LSR. this clears its error bits.
+    fn line_status(&self) -> u8 {
+        unsafe { self.getreg(LSR).read_volatile() }
+    }
+
     pub fn uart_getc(&self) -> Option<u8> {
         unsafe {
             // If the UART receiver is ready (indicated by appropriate LSR bit), get a character
@@ -103,173 +304,463 @@ impl UartDriver {
 
     pub fn uart_putc(&self, c: u8) {
         unsafe {
-            // If the UART transmitter is idle (indicated by appropriate LSR bit), send a character
-            if self.getreg(LSR).read_volatile() & LSR_TX_IDLE == 0 {
+            // wait for Transmit Holding Empty to be set in LSR.
+            while !self.tx_idle() {}
+            self.getreg(THR).write_volatile(c);
+        }
+    }
+
+    // can THR accept another character to send?
+    fn tx_idle(&self) -> bool {
+        unsafe { self.getreg(LSR).read_volatile() & LSR_TX_IDLE != 0 }
+    }
+
+}
+
+// Writes straight to the chip, waiting for each byte to go out.
+impl Write for UartDriver {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        s.bytes().for_each(|c| self.uart_putc(c));
+        Ok(())
+    }
+
+}
+
+// Writes to the console through its transmit buffer;
+// what print! uses.
+pub struct UartWriter;
+
+impl Write for UartWriter {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        s.bytes().for_each(uartputc);
+        Ok(())
+    }
+}
+
+// A serial device the kernel can talk through.
+pub trait SerialPort {
+    fn name(&self) -> &'static str;
+
+    // queue a byte for output, waiting for room if the
+    // output buffer is full.
+    fn putc(&mut self, c: u8);
+
+    // send a byte right away, bypassing the buffer and
+    // interrupts.
+    fn putc_sync(&mut self, c: u8);
+
+    // the next received byte, if any.
+    fn getc(&mut self) -> Option<u8>;
+
+    // change the line settings.
+    fn configure(&mut self, config: UartConfig) -> Result<(), UartError>;
+
+    fn config(&self) -> Option<UartConfig>;
+}
+
+// An NS16550A with its buffers and counters.
+pub struct Uart {
+    name: &'static str,
+    driver: UartDriver,
+    pub base: u64,
+    pub size: u64,
+    pub irq: u32,
+    // the transmit output buffer, drained by start().
+    tx: Ring<UART_TX_BUF_SIZE>,
+    // a process is sleeping in uartputc() for room in tx.
+    tx_waiting: bool,
+    // input bytes collected by intr(), read by getc().
+    rx: Ring<UART_RX_BUF_SIZE>,
+    // input bytes dropped because rx was full.
+    rx_dropped: u64,
+    errors: LineErrors,
+    // the settings the port is running with.
+    config: Option<UartConfig>,
+}
+
+impl Uart {
+    const fn new() -> Self {
+        Uart {
+            name: "",
+            driver: UartDriver { base_address: 0 },
+            base: 0,
+            size: 0,
+            irq: 0,
+            tx: Ring::new(),
+            tx_waiting: false,
+            rx: Ring::new(),
+            rx_dropped: 0,
+            errors: LineErrors::new(),
+            config: None,
+        }
+    }
 
+    // if the UART is idle, and a character is waiting
+    // in the transmit buffer, send it.
+    // caller must have interrupts off.
+    // called from both the top- and bottom-half.
+    fn start(&mut self) {
+        while !self.tx.is_empty() {
+            if !self.driver.tx_idle() {
+                // the UART transmit holding register is full,
+                // so we cannot give it another byte.
+                // it will interrupt when it's ready for a new byte.
+                return;
+            }
+            let c = self.tx.pop();
+            unsafe { self.driver.getreg(THR).write_volatile(c) };
+
+            // maybe uartputc() is waiting for space in the buffer.
+            if self.tx_waiting {
+                self.tx_waiting = false;
+                proc::wakeup(self.tx_chan());
+            }
+        }
+    }
+
+    // what uartputc() sleeps on.
+    fn tx_chan(&self) -> Chan {
+        &self.tx as *const _ as Chan
+    }
+
+    // push out everything buffered, synchronously.
+    // caller must have interrupts off.
+    fn flush(&mut self) {
+        while !self.tx.is_empty() {
+            self.driver.uart_putc(self.tx.pop());
+        }
+        while !self.driver.tx_idle() {}
+    }
+
+    // handle a uart interrupt, raised because input has
+    // arrived, or the uart is ready for more output, or
+    // both.
+    fn intr(&mut self) {
+        // read incoming characters into the receive buffer.
+        loop {
+            let lsr = self.driver.line_status();
+            self.errors.record(lsr);
+            if lsr & LSR_RX_READY == 0 {
+                break;
+            }
+            let c = unsafe { self.driver.getreg(RHR).read_volatile() };
+            if lsr & (LSR_PARITY | LSR_FRAMING | LSR_BREAK) != 0 {
+                // the byte is garbage.
+                continue;
+            }
+            if self.rx.is_full() {
+                self.rx_dropped += 1;
             } else {
-                self.getreg(THR).write_volatile(c);
+                self.rx.push(c);
             }
         }
+
+        // send buffered characters.
+        self.start();
+    }
+
+    pub fn rx_dropped(&self) -> u64 {
+        self.rx_dropped
     }
 
+    pub fn line_errors(&self) -> LineErrors {
+        self.errors
+    }
 }
 
-// TODO: look into Write trait and whether this makes sense
-impl Write for UartDriver {
+impl SerialPort for Uart {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // the port's lock keeps interrupts off, so this
+    // polls the UART while the buffer is full; a process
+    // sleeps in uartputc() instead.
+    fn putc(&mut self, c: u8) {
+        while self.tx.is_full() {
+            // buffer is full; wait for the UART to take a byte.
+            self.start();
+        }
+        self.tx.push(c);
+        self.start();
+    }
+
+    fn putc_sync(&mut self, c: u8) {
+        self.driver.uart_putc(c);
+    }
+
+    fn getc(&mut self) -> Option<u8> {
+        if self.rx.is_empty() {
+            None
+        } else {
+            Some(self.rx.pop())
+        }
+    }
+
+    // waits for buffered output to go out first, since
+    // reprogramming resets the FIFOs.
+    fn configure(&mut self, config: UartConfig) -> Result<(), UartError> {
+        self.flush();
+        if self.tx_waiting {
+            self.tx_waiting = false;
+            proc::wakeup(self.tx_chan());
+        }
+        let r = self.driver.init(&config);
+        if r.is_ok() {
+            self.config = Some(config);
+        }
+        r
+    }
+
+    fn config(&self) -> Option<UartConfig> {
+        self.config
+    }
+}
+
+// Writes through the port's transmit buffer.
+impl Write for Uart {
     fn write_str(&mut self, s: &str) -> Result<(), Error> {
-        // TODO: implement buffer once we have interrupts
-        s.bytes().for_each(|c| self.uart_putc(c));
+        s.bytes().for_each(|c| self.putc(c));
         Ok(())
     }
+}
+
+// the UARTs uartinit() found, in devicetree order.
+static UARTS: [SpinLock<Uart>; NUART] = [const { SpinLock::new(Uart::new(), "uart") }; NUART];
+static NUARTS: AtomicUsize = AtomicUsize::new(0);
+// which of them is the console, and its registers, for
+// output that can't wait for the port's lock.
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
+static CONSOLE_BASE: AtomicU64 = AtomicU64::new(UART0);
+
+pub fn ports() -> &'static [SpinLock<Uart>] {
+    &UARTS[..NUARTS.load(Ordering::Acquire)]
+}
+
+pub fn port(i: usize) -> Option<&'static SpinLock<Uart>> {
+    ports().get(i)
+}
 
+// the port print! and the console use.
+pub fn console() -> &'static SpinLock<Uart> {
+    match port(console_index()) {
+        Some(uart) => uart,
+        None => panic!("uart: no console"),
+    }
+}
+
+pub fn console_index() -> usize {
+    CONSOLE.load(Ordering::Relaxed)
 }
 
-// NOTE: Code from MIT 6.1810
-// // the transmit output buffer.
-// char uart_tx_buf[UART_TX_BUF_SIZE];
-// uint64 uart_tx_w; // write next to uart_tx_buf[uart_tx_w % UART_TX_BUF_SIZE]
-// uint64 uart_tx_r; // read next from uart_tx_buf[uart_tx_r % UART_TX_BUF_SIZE]
-// 
-// extern volatile int panicked; // from printf.c
-// 
-// void uartstart();
-// 
-// void
-// uartinit(void)
-// {
-//   // disable interrupts.
-//   WriteReg(IER, 0x00);
-// 
-//   // special mode to set baud rate.
-//   WriteReg(LCR, LCR_BAUD_LATCH);
-// 
-//   // LSB for baud rate of 38.4K.
-//   WriteReg(0, 0x03);
-// 
-//   // MSB for baud rate of 38.4K.
-//   WriteReg(1, 0x00);
-// 
-//   // leave set-baud mode,
-//   // and set word length to 8 bits, no parity.
-//   WriteReg(LCR, LCR_EIGHT_BITS);
-// 
-//   // reset and enable FIFOs.
-//   WriteReg(FCR, FCR_FIFO_ENABLE | FCR_FIFO_CLEAR);
-// 
-//   // enable transmit and receive interrupts.
-//   WriteReg(IER, IER_TX_ENABLE | IER_RX_ENABLE);
-// 
-//   initlock(&uart_tx_lock, "uart");
-// }
-// 
-// // add a character to the output buffer and tell the
-// // UART to start sending if it isn't already.
-// // blocks if the output buffer is full.
-// // because it may block, it can't be called
-// // from interrupts; it's only suitable for use
-// // by write().
-// void
-// uartputc(int c)
-// {
-//   acquire(&uart_tx_lock);
-// 
-//   if(panicked){
-//     for(;;)
-//       ;
-//   }
-//   while(uart_tx_w == uart_tx_r + UART_TX_BUF_SIZE){
-//     // buffer is full.
-//     // wait for uartstart() to open up space in the buffer.
-//     sleep(&uart_tx_r, &uart_tx_lock);
-//   }
-//   uart_tx_buf[uart_tx_w % UART_TX_BUF_SIZE] = c;
-//   uart_tx_w += 1;
-//   uartstart();
-//   release(&uart_tx_lock);
-// }
-// 
-// 
-// // alternate version of uartputc() that doesn't 
-// // use interrupts, for use by kernel printf() and
-// // to echo characters. it spins waiting for the uart's
-// // output register to be empty.
-// void
-// uartputc_sync(int c)
-// {
-//   push_off();
-// 
-//   if(panicked){
-//     for(;;)
-//       ;
-//   }
-// 
-//   // wait for Transmit Holding Empty to be set in LSR.
-//   while((ReadReg(LSR) & LSR_TX_IDLE) == 0)
-//     ;
-//   WriteReg(THR, c);
-// 
-//   pop_off();
-// }
-// 
-// // if the UART is idle, and a character is waiting
-// // in the transmit buffer, send it.
-// // caller must hold uart_tx_lock.
-// // called from both the top- and bottom-half.
-// void
-// uartstart()
-// {
-//   while(1){
-//     if(uart_tx_w == uart_tx_r){
-//       // transmit buffer is empty.
-//       return;
-//     }
-//     
-//     if((ReadReg(LSR) & LSR_TX_IDLE) == 0){
-//       // the UART transmit holding register is full,
-//       // so we cannot give it another byte.
-//       // it will interrupt when it's ready for a new byte.
-//       return;
-//     }
-//     
-//     int c = uart_tx_buf[uart_tx_r % UART_TX_BUF_SIZE];
-//     uart_tx_r += 1;
-//     
-//     // maybe uartputc() is waiting for space in the buffer.
-//     wakeup(&uart_tx_r);
-//     
-//     WriteReg(THR, c);
-//   }
-// }
-// 
-// // read one input character from the UART.
-// // return -1 if none is waiting.
-// int
-// uartgetc(void)
-// {
-//   if(ReadReg(LSR) & 0x01){
-//     // input data is ready.
-//     return ReadReg(RHR);
-//   } else {
-//     return -1;
-//   }
-// }
-// 
-// // handle a uart interrupt, raised because input has
-// // arrived, or the uart is ready for more output, or
-// // both. called from devintr().
-// void
-// uartintr(void)
-// {
-//   // read and process incoming characters.
-//   while(1){
-//     int c = uartgetc();
-//     if(c == -1)
-//       break;
-//     consoleintr(c);
-//   }
-// 
-//   // send buffered characters.
-//   acquire(&uart_tx_lock);
-//   uartstart();
-//   release(&uart_tx_lock);
-// }
+// make port i the console.
+pub fn set_console(i: usize) -> bool {
+    let Some(uart) = port(i) else {
+        return false;
+    };
+    let base = uart.lock().base;
+    console().lock().flush();
+    CONSOLE.store(i, Ordering::Relaxed);
+    CONSOLE_BASE.store(base, Ordering::Relaxed);
+    true
+}
+
+fn add_port(name: &'static str, base: u64, size: u64, irq: u32, config: UartConfig) {
+    let n = NUARTS.load(Ordering::Relaxed);
+    if n == NUART {
+        println!("uart: no room for {}", name);
+        return;
+    }
+    let err = {
+        let mut uart = UARTS[n].lock();
+        uart.name = name;
+        uart.driver = UartDriver::new(base);
+        uart.base = base;
+        uart.size = size;
+        uart.irq = irq;
+        let err = uart.configure(config).err();
+        if err.is_some() {
+            // can't happen with the default baud rate unless the
+            // devicetree's clock is far off; fall back to qemu's.
+            uart.configure(UartConfig::default()).unwrap();
+        }
+        err
+    };
+    NUARTS.store(n + 1, Ordering::Release);
+    if let Some(e) = err {
+        println!("{}: {}, using the default clock", name, e);
+    }
+    plic::register(irq, name, uartintr);
+}
+
+// Find the UARTs in the devicetree, or use qemu's UART0 if
+// there isn't one, set them up and ask the PLIC for their
+// interrupts. The console is the one /chosen's stdout-path
+// names, or else the first.
+pub fn uartinit() {
+    if let Some(fdt) = fdt::get() {
+        for node in fdt.compatible("ns16550a") {
+            let (Some((base, size)), Some(irq)) = (node.reg(), node.prop_u32("interrupts")) else {
+                continue;
+            };
+            add_port(node.name, base, size, irq, UartConfig::for_uart(base));
+        }
+    }
+    if ports().is_empty() {
+        add_port("uart0", UART0, UART0_SIZE, UART0_IRQ, UartConfig::default());
+    }
+
+    let console = stdout_base()
+        .and_then(|base| ports().iter().position(|u| u.lock().base == base))
+        .unwrap_or(0);
+    set_console(console);
+}
+
+// The address of the UART /chosen's stdout-path refers to.
+// stdout-path is a path or an alias, optionally followed by
+// ":" and options like the baud rate.
+fn stdout_base() -> Option<u64> {
+    let fdt = fdt::get()?;
+    let path = fdt.find("/chosen")?.prop_str("stdout-path")?;
+    let path = path.split(':').next()?;
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        fdt.find("/aliases")?.prop_str(path)?
+    };
+    fdt.find(path)?.reg().map(|(base, _)| base)
+}
+
+// called by plic::intr() for any UART's irq.
+// the console's input goes on to the line discipline,
+// without the port's lock, since consoleintr() echoes.
+fn uartintr(irq: u32) {
+    let console = console_index();
+    for (i, port) in ports().iter().enumerate() {
+        {
+            let mut uart = port.lock();
+            if uart.irq != irq {
+                continue;
+            }
+            uart.intr();
+        }
+        if i == console {
+            loop {
+                let Some(c) = port.lock().getc() else {
+                    break;
+                };
+                console::consoleintr(c);
+            }
+        }
+    }
+}
+
+// console output through the transmit buffer, or synchronously
+// once the kernel has panicked. if the buffer is full, a process
+// that holds no spin lock sleeps until uartintr() has sent some
+// of it; anyone else waits for the UART with interrupts off.
+// before uartinit() there is no console port yet, so this
+// talks to qemu's UART0 directly.
+pub fn uartputc(c: u8) {
+    if PANICKED.load(Ordering::Relaxed) {
+        uartputc_sync(c);
+        return;
+    }
+    let Some(port) = port(console_index()) else {
+        UartDriver::new(UART0).uart_putc(c);
+        return;
+    };
+    let mut uart = port.lock();
+    while uart.tx.is_full() && proc::may_sleep() {
+        uart.tx_waiting = true;
+        let chan = uart.tx_chan();
+        uart = proc::sleep(chan, uart);
+    }
+    uart.putc(c);
+}
+
+// alternate version of uartputc() that doesn't
+// use the buffer, interrupts or the port's lock.
+pub fn uartputc_sync(c: u8) {
+    spinlock::push_off();
+    UartDriver::new(CONSOLE_BASE.load(Ordering::Relaxed)).uart_putc(c);
+    spinlock::pop_off();
+}
+
+// serialize print! among harts. print() formats into a buffer
+// and sends it in pieces no bigger than the transmit buffer,
+// each under PRINT.
+// only waiting for room, which may sleep, happens outside it, so
+// interrupt handlers and code holding spin locks can take PRINT
+// too; a piece that still doesn't fit polls the UART. output
+// longer than one piece may be interleaved between pieces.
+static PRINT: SpinLock<()> = SpinLock::new((), "print");
+
+struct PrintBuf {
+    buf: [u8; UART_TX_BUF_SIZE],
+    n: usize,
+}
+
+impl PrintBuf {
+    fn flush(&mut self) {
+        if self.n == 0 {
+            return;
+        }
+        wait_for_room(self.n);
+        let _guard = PRINT.lock();
+        self.buf[..self.n].iter().for_each(|&c| uartputc(c));
+        self.n = 0;
+    }
+}
+
+impl Write for PrintBuf {
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        for c in s.bytes() {
+            if self.n == self.buf.len() {
+                self.flush();
+            }
+            self.buf[self.n] = c;
+            self.n += 1;
+        }
+        Ok(())
+    }
+}
+
+// sleep until the console's transmit buffer has room for n
+// bytes, if the caller may sleep.
+fn wait_for_room(n: usize) {
+    let Some(port) = port(console_index()) else {
+        return;
+    };
+    let mut uart = port.lock();
+    while uart.tx.room() < n && proc::may_sleep() {
+        uart.tx_waiting = true;
+        let chan = uart.tx_chan();
+        uart = proc::sleep(chan, uart);
+    }
+}
+
+// print! and println! come here. once the kernel has
+// panicked, output skips the locks, which the panicking
+// hart may hold.
+pub fn print(args: fmt::Arguments) {
+    if PANICKED.load(Ordering::Relaxed) {
+        let _ = UartWriter.write_fmt(args);
+        return;
+    }
+    let mut out = PrintBuf { buf: [0; UART_TX_BUF_SIZE], n: 0 };
+    let _ = out.write_fmt(args);
+    out.flush();
+}
+
+// The kernel is panicking: push out whatever is still buffered,
+// then send everything after it synchronously, since interrupts
+// may never come again. a console port that is locked (perhaps
+// by the panicking hart) keeps its buffer.
+pub fn panicked() {
+    riscv::intr_off();
+    if let Some(mut uart) = port(console_index()).and_then(|u| u.try_lock()) {
+        uart.flush();
+    }
+    PANICKED.store(true, Ordering::Relaxed);
+}
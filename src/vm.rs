@@ -1,34 +1,458 @@
+// Sv39 page tables.
+// Based on vm.c from MIT 6.1810 (xv6).
+//
+// A page table is one 4096-byte page holding 512 PTEs. Sv39 uses three
+// levels: the top 27 bits of a 39-bit virtual address are split into
+// three 9-bit indices (PX!), one per level, and the low 12 bits are the
+// offset within the page.
+use crate::ipi;
+use crate::kalloc;
 use crate::memlayout;
+use crate::proc;
+use crate::spinlock::SpinLock;
+use crate::uart;
+use crate::riscv::{self, PGSIZE, MAXVA, PTE_V, PTE_R, PTE_W, PTE_X};
 use crate::{print, println};
-use core::cell;
+use crate::{MAKE_SATP, PA2PTE, PTE2PA, PTE_FLAGS, PX, PGROUNDDOWN, PGROUNDUP};
+use core::fmt;
 
-pub fn testing() { 
+// the kernel's page table, shared by all harts.
+static KERNEL_PAGETABLE: SpinLock<Option<&'static mut PageTable>> = SpinLock::new(None, "kpgtbl");
+
+pub fn testing() {
     println!("Kernel base: 0x{:x}", memlayout::KERNBASE);
     println!("End of vm: 0x{:x}", memlayout::PHYSTOP);
 }
 
-// The reference to the struct Run must live as long as the struct
-struct Run<'a>{
-    run: cell::Cell<&'a Run<'a>>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    // the virtual address is at or above MAXVA.
+    OutOfRange(u64),
+    // the virtual address or size is not page-aligned.
+    Misaligned(u64),
+    // map_pages() found a valid PTE for this virtual address.
+    Remap(u64),
+    // there is no valid leaf PTE for this virtual address.
+    NotMapped(u64),
+    // the PTE for this virtual address points to a page-table page.
+    NotLeaf(u64),
+    // walk() found a leaf PTE above level 0.
+    Superpage(u64),
+    // free() found a leaf that is still mapped.
+    StillMapped(u64),
+    // no memory for a page-table page.
+    OutOfMemory,
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::OutOfRange(va) => write!(f, "va 0x{:x} is beyond MAXVA", va),
+            VmError::Misaligned(va) => write!(f, "0x{:x} is not page-aligned", va),
+            VmError::Remap(va) => write!(f, "va 0x{:x} is already mapped", va),
+            VmError::NotMapped(va) => write!(f, "va 0x{:x} is not mapped", va),
+            VmError::NotLeaf(va) => write!(f, "va 0x{:x} does not map a page", va),
+            VmError::Superpage(va) => write!(f, "va 0x{:x} is inside a superpage", va),
+            VmError::StillMapped(va) => write!(f, "va 0x{:x} is still mapped", va),
+            VmError::OutOfMemory => write!(f, "out of memory for page tables"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Pte(u64);
+
+impl Pte {
+    pub fn new(pa: u64, flags: u64) -> Self {
+        Pte(PA2PTE!(pa) | flags)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 & PTE_V != 0
+    }
+
+    // a valid PTE with any of R/W/X set maps a page; one without
+    // points to the next level of the page table.
+    pub fn is_leaf(&self) -> bool {
+        self.is_valid() && self.0 & (PTE_R | PTE_W | PTE_X) != 0
+    }
+
+    pub fn pa(&self) -> u64 {
+        PTE2PA!(self.0)
+    }
+
+    pub fn flags(&self) -> u64 {
+        PTE_FLAGS!(self.0)
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0
+    }
+
+    pub fn clear(&mut self) {
+        self.0 = 0;
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    entries: [Pte; 512],
+}
+
+impl PageTable {
+    // Allocate an empty page table.
+    pub fn create() -> Option<&'static mut PageTable> {
+        let pa = kalloc::kzalloc()?;
+        Some(unsafe { &mut *(pa as *mut PageTable) })
+    }
+
+    // the page table stored in the physical page at pa.
+    fn at(pa: u64) -> &'static mut PageTable {
+        unsafe { &mut *(pa as *mut PageTable) }
+    }
+
+    pub fn pa(&self) -> u64 {
+        self as *const PageTable as u64
+    }
+
+    // Return the PTE in this page table that corresponds to virtual
+    // address va. If alloc is true, create any required page-table
+    // pages.
+    //
+    // A PTE at each level holds the physical address of the
+    // page-table page for the next level:
+    //   level 2 -> level 1 -> level 0 -> leaf PTE for the page.
+    pub fn walk(&mut self, va: u64, alloc: bool) -> Result<&mut Pte, VmError> {
+        if va >= MAXVA {
+            return Err(VmError::OutOfRange(va));
+        }
+
+        let mut pagetable: &mut PageTable = self;
+        for level in (1..=2).rev() {
+            let pte = pagetable.entries[PX!(level, va) as usize];
+            if pte.is_valid() {
+                if pte.is_leaf() {
+                    return Err(VmError::Superpage(va));
+                }
+                pagetable = PageTable::at(pte.pa());
+            } else {
+                if !alloc {
+                    return Err(VmError::NotMapped(va));
+                }
+                let next = PageTable::create().ok_or(VmError::OutOfMemory)?;
+                pagetable.entries[PX!(level, va) as usize] = Pte::new(next.pa(), PTE_V);
+                pagetable = next;
+            }
+        }
+        Ok(&mut pagetable.entries[PX!(0, va) as usize])
+    }
+
+    // Look up a virtual address, return the physical address it maps
+    // to (including the offset within the page).
+    pub fn translate(&mut self, va: u64) -> Result<u64, VmError> {
+        let pte = *self.walk(va, false)?;
+        if !pte.is_valid() {
+            return Err(VmError::NotMapped(va));
+        }
+        Ok(pte.pa() | (va & (PGSIZE - 1)))
+    }
+
+    // Create PTEs for virtual addresses starting at va that refer to
+    // physical addresses starting at pa. va and size must be
+    // page-aligned. On error, mappings made so far are left in place.
+    pub fn map_pages(&mut self, va: u64, size: u64, pa: u64, perm: u64) -> Result<(), VmError> {
+        if !va.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(va));
+        }
+        if !size.is_multiple_of(PGSIZE) || size == 0 {
+            return Err(VmError::Misaligned(size));
+        }
+        if va.checked_add(size).is_none_or(|end| end > MAXVA) {
+            return Err(VmError::OutOfRange(va));
+        }
+
+        let last = va + size - PGSIZE;
+        let mut a = va;
+        let mut pa = pa;
+        loop {
+            let pte = self.walk(a, true)?;
+            if pte.is_valid() {
+                return Err(VmError::Remap(a));
+            }
+            *pte = Pte::new(pa, perm | PTE_V);
+            if a == last {
+                break;
+            }
+            a += PGSIZE;
+            pa += PGSIZE;
+        }
+        Ok(())
+    }
+
+    // Remove mappings starting from va, at most UNMAP_BATCH pages
+    // of the npages asked for. va must be page-aligned, and all
+    // npages mappings must exist. Optionally free the physical
+    // memory.
+    // Other harts may have the old translations cached, so this
+    // only clears the PTEs; the caller must finish() the returned
+    // batch, which shoots them down everywhere and then frees the
+    // pages, after dropping any locks, and unmap() again from
+    // va + batch.npages() pages for the rest.
+    pub fn unmap(&mut self, va: u64, npages: u64, do_free: bool) -> Result<Unmapped, VmError> {
+        if !va.is_multiple_of(PGSIZE) {
+            return Err(VmError::Misaligned(va));
+        }
+        let end = npages
+            .checked_mul(PGSIZE)
+            .and_then(|size| va.checked_add(size))
+            .ok_or(VmError::OutOfRange(va))?;
+
+        // check every page first, so that nothing is
+        // half-unmapped on error.
+        let mut a = va;
+        while a < end {
+            let pte = self.walk(a, false)?;
+            if !pte.is_valid() {
+                return Err(VmError::NotMapped(a));
+            }
+            if !pte.is_leaf() {
+                return Err(VmError::NotLeaf(a));
+            }
+            a += PGSIZE;
+        }
+
+        let mut batch = Unmapped { va, npages: 0, pages: [0; UNMAP_BATCH], n: 0 };
+        let mut a = va;
+        while a < end && batch.npages < UNMAP_BATCH as u64 {
+            let pte = self.walk(a, false)?;
+            if do_free {
+                batch.pages[batch.n] = pte.pa();
+                batch.n += 1;
+            }
+            pte.clear();
+            batch.npages += 1;
+            a += PGSIZE;
+        }
+        Ok(batch)
+    }
+
+    // the first virtual address still mapped by a leaf below this
+    // page table, which maps addresses starting at base.
+    fn find_leaf(&self, base: u64, level: u64) -> Option<u64> {
+        for (i, pte) in self.entries.iter().enumerate() {
+            let va = base | ((i as u64) << crate::PXSHIFT!(level));
+            if pte.is_leaf() {
+                return Some(va);
+            }
+            if pte.is_valid() && level > 0 {
+                if let Some(va) = PageTable::at(pte.pa()).find_leaf(va, level - 1) {
+                    return Some(va);
+                }
+            }
+        }
+        None
+    }
+
+    fn free_walk(&mut self) {
+        for pte in self.entries.iter_mut() {
+            if pte.is_valid() {
+                PageTable::at(pte.pa()).free_walk();
+                pte.clear();
+            }
+        }
+        kalloc::kfree(self.pa());
+    }
+
+    // Recursively free the page-table pages, including this one.
+    // All leaf mappings must already have been removed with unmap().
+    pub fn free(&mut self) -> Result<(), VmError> {
+        if let Some(va) = self.find_leaf(0, 2) {
+            return Err(VmError::StillMapped(va));
+        }
+        self.free_walk();
+        Ok(())
+    }
+
+    // Print the valid PTEs, one level per indentation step.
+    pub fn print(&self) {
+        println!("page table 0x{:x}", self.pa());
+        self.print_level(0, 2);
+    }
+
+    fn print_level(&self, base: u64, level: u64) {
+        for (i, pte) in self.entries.iter().enumerate() {
+            if !pte.is_valid() {
+                continue;
+            }
+            let va = base | ((i as u64) << crate::PXSHIFT!(level));
+            for _ in level..3 {
+                print!(" ..");
+            }
+            println!("{}: va 0x{:x} pte 0x{:x} pa 0x{:x}", i, PGROUNDDOWN!(va), pte.bits(), pte.pa());
+            if !pte.is_leaf() && level > 0 {
+                PageTable::at(pte.pa()).print_level(va, level - 1);
+            }
+        }
+    }
+}
+
+// Make a direct-map page table for the kernel.
+fn kvmmake() -> &'static mut PageTable {
+    let kpgtbl = match PageTable::create() {
+        Some(pt) => pt,
+        None => panic!("kvmmake: out of memory"),
+    };
+
+    // uart registers, for every port uartinit() found.
+    for u in uart::ports().iter() {
+        let (start, size) = {
+            let u = u.lock();
+            (u.base, u.size)
+        };
+        let base = PGROUNDDOWN!(start);
+        if kpgtbl.translate(base).is_err() {
+            kvmmap(kpgtbl, base, base, PGROUNDUP!(start + size) - base, PTE_R | PTE_W);
+        }
+    }
+
+    // qemu's test device, for poweroff and reboot
+    kvmmap(kpgtbl, memlayout::VIRT_TEST, memlayout::VIRT_TEST, memlayout::VIRT_TEST_SIZE, PTE_R | PTE_W);
+
+    // virtio mmio disk interface
+    kvmmap(kpgtbl, memlayout::VIRTIO0, memlayout::VIRTIO0, memlayout::VIRTIO0_SIZE, PTE_R | PTE_W);
+
+    // CLINT, for mtime and mtimecmp
+    kvmmap(kpgtbl, memlayout::CLINT, memlayout::CLINT, memlayout::CLINT_SIZE, PTE_R | PTE_W);
+
+    // PLIC
+    kvmmap(kpgtbl, memlayout::PLIC, memlayout::PLIC, memlayout::PLIC_SIZE, PTE_R | PTE_W);
+
+    let (text_start, text_end, rodata_start, data_start) = unsafe {
+        (
+            memlayout::TEXT_START,
+            memlayout::TEXT_END,
+            memlayout::RODATA_START,
+            memlayout::DATA_START,
+        )
+    };
+
+    // map kernel text executable and read-only.
+    kvmmap(kpgtbl, text_start, text_start, text_end - text_start, PTE_R | PTE_X);
+
+    // map kernel rodata (and anything else the linker put between
+    // it and .data, like .eh_frame) read-only.
+    kvmmap(kpgtbl, rodata_start, rodata_start, data_start - rodata_start, PTE_R);
+
+    // map kernel data, bss, the boot stack and the physical RAM
+    // the allocators hand out.
+    kvmmap(kpgtbl, data_start, data_start, memlayout::PHYSTOP - data_start, PTE_R | PTE_W);
+
+    // make room for each process's kernel stack.
+    proc::proc_mapstacks(kpgtbl);
+
+    kpgtbl
+}
+
+// add a mapping to the kernel page table.
+// only used when booting.
+// does not flush TLB or enable paging.
+fn kvmmap(kpgtbl: &mut PageTable, va: u64, pa: u64, sz: u64, perm: u64) {
+    if let Err(e) = kpgtbl.map_pages(va, sz, pa, perm) {
+        panic!("kvmmap: {}", e);
+    }
+}
+
+// Initialize the one kernel_pagetable
+pub fn kvminit() {
+    let kpgtbl = kvmmake();
+    *KERNEL_PAGETABLE.lock() = Some(kpgtbl);
 }
 
-struct KernMem<'a> {
-    freelist: cell::Cell<&'a Run<'a>>,
+// Run f on the kernel page table, holding its lock. Interrupts
+// are off meanwhile, so f can't shoot down TLBs; what it
+// unmap()s must be finish()ed after this returns.
+pub fn with_kernel_pagetable<R>(f: impl FnOnce(&mut PageTable) -> R) -> R {
+    match KERNEL_PAGETABLE.lock().as_deref_mut() {
+        Some(kpgtbl) => f(kpgtbl),
+        None => panic!("with_kernel_pagetable: before kvminit"),
+    }
 }
 
-// NOTE: Code from MIT 6.1810
-// void
-// kinit()
-// {
-//   initlock(&kmem.lock, "kmem");
-//   freerange(end, (void*)PHYSTOP);
-// }
-// 
-// void
-// freerange(void *pa_start, void *pa_end)
-// {
-//   char *p;
-//   p = (char*)PGROUNDUP((uint64)pa_start);
-//   for(; p + PGSIZE <= (char*)pa_end; p += PGSIZE)
-//     kfree(p);
-// }
+// Remove npages of kernel mappings starting at va, optionally
+// freeing the memory. The caller must have interrupts on and
+// hold no spin locks, since each batch is shot down from the
+// other harts' TLBs once the page table's lock is dropped.
+pub fn kvmunmap(mut va: u64, mut npages: u64, do_free: bool) -> Result<(), VmError> {
+    loop {
+        let batch = with_kernel_pagetable(|kpgtbl| kpgtbl.unmap(va, npages, do_free))?;
+        va += batch.npages() * PGSIZE;
+        npages -= batch.npages();
+        batch.finish();
+        if npages == 0 {
+            return Ok(());
+        }
+    }
+}
+
+// most pages unmap() takes out at once.
+const UNMAP_BATCH: usize = 32;
+
+// Pages unmap() took out of a page table, still to be flushed
+// from the TLBs, and the physical pages to free after that.
+pub struct Unmapped {
+    va: u64,
+    npages: u64,
+    pages: [u64; UNMAP_BATCH],
+    n: usize,
+}
+
+impl Unmapped {
+    // how many pages were unmapped, starting at va.
+    pub fn npages(&self) -> u64 {
+        self.npages
+    }
+
+    // Shoot the translations down on every hart, then free the
+    // pages. Needs interrupts on, like tlb_shootdown().
+    pub fn finish(self) {
+        if self.npages > 0 {
+            tlb_shootdown(self.va, self.npages);
+        }
+        self.pages[..self.n].iter().for_each(|&pa| kalloc::kfree(pa));
+    }
+}
+
+// beyond this many pages, flush the whole TLB rather than
+// one page at a time.
+const FLUSH_PAGES: u64 = 32;
+
+fn flush_tlb(va: u64, npages: u64) {
+    if npages > FLUSH_PAGES {
+        riscv::sfence_vma();
+    } else {
+        for i in 0..npages {
+            riscv::sfence_vma_va(va + i * PGSIZE);
+        }
+    }
+}
+
+// Flush the translations for npages starting at va from every
+// hart's TLB, after their PTEs have changed.
+pub fn tlb_shootdown(va: u64, npages: u64) {
+    // make the PTE changes visible before any hart flushes.
+    riscv::sfence_vma();
+    ipi::call_all(flush_tlb, va, npages);
+}
+
+// Switch h/w page table register to the kernel's page table,
+// and enable paging.
+pub fn kvminithart() {
+    // wait for any previous writes to the page table memory to finish.
+    riscv::sfence_vma();
+
+    riscv::w_satp(MAKE_SATP!(with_kernel_pagetable(|kpgtbl| kpgtbl.pa())));
+
+    // flush stale entries from the TLB.
+    riscv::sfence_vma();
+}